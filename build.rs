@@ -1,9 +1,29 @@
 // const F021_LIBRARY: &str = "F021_API_CortexR4_BE_L2FMC.lib";
-const F021_LIBRARY_STRIPPED: &str = "F021_API_CortexR4_BE_L2FMC_NDS.lib";
+
+/// Select the stripped F021 library that matches the byte order of the target
+/// and the flash controller of the device, e.g. `F021_API_CortexR4_BE_L2FMC_NDS.lib`.
+fn f021_library() -> String {
+    let endianness = match std::env::var("CARGO_CFG_TARGET_ENDIAN").as_deref() {
+        Ok("little") => "LE",
+        _ => "BE",
+    };
+    // The TMS570LS31x/RM48x family does not have the L2FMC
+    let controller = if std::env::var_os("CARGO_FEATURE_LS3137").is_some() {
+        ""
+    } else {
+        "_L2FMC"
+    };
+    format!("F021_API_CortexR4_{}{}_NDS.lib", endianness, controller)
+}
+
 fn main() {
+    // The simulation doesn't need the F021 library
+    if std::env::var_os("CARGO_FEATURE_SIM").is_some() {
+        return;
+    }
     println!(
         "cargo::rustc-link-arg={}/{}",
         env!("CARGO_MANIFEST_DIR"),
-        F021_LIBRARY_STRIPPED
+        f021_library()
     );
 }
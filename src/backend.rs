@@ -0,0 +1,354 @@
+//! The interface between the algorithm and the flash controller. `Algorithm` only
+//! talks to the flash through a `FlashBackend`, so the same erase and program logic
+//! can drive the F021 API, the simulation of it, or another TI flash controller.
+
+use crate::checksum;
+use crate::device::{self, EWAIT, FBPWRMODE, FMAC, FRDCNTL, FSM_EXECUTE, FSM_WR_ENA};
+use crate::f021::{
+    self, Error, FlashBank, FlashBankSectors, FlashProgrammingCommand, FlashStatus,
+    FsmStatusRegister, ReadMode,
+};
+use crate::wait_states::WaitStates;
+use rtt_target::{rprint, rprintln};
+
+/// Set to `true` to print version information on init.
+const PRINT_VERSION_INFORMATION: bool = false;
+
+/// The SUSPEND_NOW field of FSM_EXECUTE, and the value that suspends the current
+/// operation. The FSMEXECUTE field in the low bits has to be left alone, since
+/// writing 0x15 to it starts the command in FSM_COMMAND again.
+const FSM_EXECUTE_SUSPEND_NOW: u32 = 0xf << 16;
+const FSM_EXECUTE_SUSPEND: u32 = 0x5 << 16;
+
+/// The operations that the algorithm needs from a flash controller.
+///
+/// Erase and program commands return as soon as the command has been accepted.
+/// The caller waits for `fsm_status()` to stop reporting busy, and then checks it
+/// for errors.
+pub trait FlashBackend: Default {
+    /// The controller state that `initialize()` changes, so that it can be restored.
+    type State;
+
+    /// Power up the banks and prepare the controller for an HCLK of `clock` MHz.
+    /// Returns the previous state of the controller, to be passed to `restore()`
+    /// once the algorithm is done.
+    fn initialize(&self, clock: u32) -> Result<Self::State, Error>;
+
+    /// Put the controller back the way it was before `initialize()`.
+    fn restore(&self, state: Self::State);
+
+    /// The layout of `bank`, or an error if there is no such bank.
+    fn bank_sectors(&self, bank: FlashBank) -> Result<FlashBankSectors, Error>;
+
+    /// Select the bank that subsequent commands apply to.
+    fn set_active_bank(&self, bank: FlashBank) -> Result<(), Error>;
+
+    /// Allow the sectors of the active bank in `sectors` to be erased and programmed.
+    /// `eeprom` is `true` if the active bank is an EEPROM emulation bank.
+    fn enable_sectors(&self, eeprom: bool, sectors: u64) -> Result<(), Error>;
+
+    /// Allow or forbid programming of the customer OTP of `bank`.
+    fn set_otp_writable(&self, bank: FlashBank, writable: bool);
+
+    /// The number of data bytes in one row of the main flash banks, or of the EEPROM
+    /// emulation bank if `eeprom` is `true`.
+    fn row_width(&self, eeprom: bool) -> usize;
+
+    /// Clear any errors reported by the previous command.
+    fn clear_status(&self) -> Result<(), Error>;
+
+    /// Clear the errors and the remaining state of the previous command, which is
+    /// used to recover a stuck FSM.
+    fn clear_more(&self) -> Result<(), Error>;
+
+    /// Start erasing the sector that contains `address`.
+    fn erase_sector(&self, address: u32) -> Result<(), Error>;
+
+    /// Suspend the erase in progress. The FSM stops at the end of the current erase
+    /// pulse, and then reports ESUSP until the erase is resumed.
+    fn suspend_erase(&self);
+
+    /// Continue an erase that was suspended by `suspend_erase()`.
+    fn resume_erase(&self) -> Result<(), Error>;
+
+    /// Start programming `data`, and possibly `ecc`, to `address`. The data may not
+    /// cross a row of the bank.
+    fn program(
+        &self,
+        address: u32,
+        data: &[u8],
+        ecc: Option<&[u8]>,
+        mode: FlashProgrammingCommand,
+    ) -> Result<(), Error>;
+
+    /// Check that `size` bytes at `address` are erased. `address` must be word aligned.
+    fn blank_check(&self, address: u32, size: u32) -> Result<(), FlashStatus>;
+
+    /// Check that `size` bytes at `address` are erased, one byte at a time.
+    fn blank_check_bytewise(&self, address: u32, size: u32) -> Result<(), FlashStatus>;
+
+    /// Compare the flash at the word-aligned `address` against `data`.
+    fn verify(&self, address: u32, data: &[u32]) -> Result<(), FlashStatus>;
+
+    /// Compare the flash at `address` against `data`, one byte at a time.
+    fn verify_bytewise(&self, address: u32, data: &[u8]) -> Result<(), FlashStatus>;
+
+    /// Read `buffer.len()` bytes of flash starting at `address`.
+    fn read(&self, address: u32, buffer: &mut [u8]);
+
+    /// Read `buffer.len()` bytes of flash starting at `address` in `mode`.
+    fn margin_read(&self, address: u32, buffer: &mut [u8], mode: ReadMode) -> Result<(), Error>;
+
+    /// The PSA signature of the `words` 32-bit words of flash at `address`,
+    /// calculated from a seed of 0 with a normal read.
+    fn psa(&self, address: u32, words: u32) -> u32;
+
+    /// The Fletcher-32 checksum of `size` bytes of flash at `address`.
+    fn fletcher32(&self, address: u32, size: u32) -> u32;
+
+    /// Read flash like `read()`, but with ECC checking turned off so that erased
+    /// words, whose ECC isn't valid, read back exactly as they are. This only covers
+    /// flash on the ATCM, so not main flash on the TMS570LC43x.
+    fn read_without_ecc(&self, address: u32, buffer: &mut [u8]);
+
+    /// The state of the flash state machine.
+    fn fsm_status(&self) -> FsmStatusRegister;
+
+    /// Flush the read pipeline so that reads see the result of the last command.
+    fn flush(&self);
+}
+
+/// The F021 Flash API. With the `sim` feature this is the simulation of the API.
+#[derive(Default)]
+pub struct F021;
+
+/// The registers that `F021::initialize()` changes.
+pub struct F021State {
+    fbpwrmode: u32,
+    frdcntl: u32,
+    ewait: u32,
+    fmac: u32,
+}
+
+/// Run `write` with the flash wrapper registers unlocked through FSM_WR_ENA.
+fn write_protected(write: impl FnOnce()) {
+    f021::write_register(FSM_WR_ENA, 5);
+    write();
+    f021::write_register(FSM_WR_ENA, 2);
+}
+
+impl FlashBackend for F021 {
+    type State = F021State;
+
+    fn initialize(&self, clock: u32) -> Result<F021State, Error> {
+        f021::invalidate_caches();
+        let state = F021State {
+            fbpwrmode: f021::read_register(FBPWRMODE),
+            frdcntl: f021::read_register(FRDCNTL),
+            ewait: f021::read_register(EWAIT),
+            fmac: f021::read_register(FMAC),
+        };
+
+        // Ensure the EEPROM is powered up, since that is read by `initialize_flash_banks()`.
+        if state.fbpwrmode & 0xffff != 0xffff {
+            rprintln!(
+                "FBPWRMODE was {:04x} and not 0xffff -- fixing",
+                state.fbpwrmode
+            );
+            f021::write_register(FBPWRMODE, device::FBPWRMODE_ALL_ACTIVE);
+        }
+
+        // Wait states have to be set before the flash is read at the new clock, and
+        // `initialize_flash_banks()` checks that they are enough for it.
+        let limits = &device::WAIT_STATE_LIMITS;
+        if clock > limits.max_hclk_mhz {
+            rprintln!(
+                "HCLK of {} MHz is above the rated {} MHz",
+                clock,
+                limits.max_hclk_mhz
+            );
+        }
+        let wait_states = WaitStates::for_hclk(clock, limits);
+        rprintln!(
+            "Current RWAIT: {}, EWAIT: {}",
+            (state.frdcntl >> 8) & 15,
+            (state.ewait >> 16) & 15
+        );
+        rprintln!(
+            "Setting RWAIT to {}, EWAIT to {}",
+            wait_states.rwait,
+            wait_states.ewait
+        );
+        let frdcntl =
+            (state.frdcntl & !(15 << 8)) | (wait_states.rwait << 8) | device::FRDCNTL_ENABLES;
+        let ewait = (state.ewait & !(15 << 16)) | (wait_states.ewait << 16);
+        write_protected(|| {
+            f021::write_register(FRDCNTL, frdcntl);
+            f021::write_register(EWAIT, ewait);
+        });
+
+        rprintln!("Calling initialize_flash_banks({})...", clock);
+        if let Err(e) = f021::initialize_flash_banks(clock) {
+            // `new()` fails without an algorithm to restore the controller on drop
+            self.restore(state);
+            return Err(e);
+        }
+
+        if PRINT_VERSION_INFORMATION {
+            rprint!("Getting library info:");
+            let library_info = f021::library_info();
+            rprintln!("   {:x?}", library_info);
+
+            rprint!("Getting device info: ");
+            let device_info = f021::device_info();
+            rprintln!("   {:x?}", device_info);
+        }
+
+        Ok(state)
+    }
+
+    fn restore(&self, state: F021State) {
+        f021::write_register(FMAC, state.fmac);
+        write_protected(|| {
+            f021::write_register(FRDCNTL, state.frdcntl);
+            f021::write_register(EWAIT, state.ewait);
+        });
+        f021::write_register(FBPWRMODE, state.fbpwrmode);
+        f021::invalidate_caches();
+    }
+
+    fn bank_sectors(&self, bank: FlashBank) -> Result<FlashBankSectors, Error> {
+        f021::bank_sectors(bank)
+    }
+
+    fn set_active_bank(&self, bank: FlashBank) -> Result<(), Error> {
+        f021::set_active_flash_bank(bank)?;
+        Ok(())
+    }
+
+    fn enable_sectors(&self, eeprom: bool, sectors: u64) -> Result<(), Error> {
+        // EEPROM emulation banks have their own, wider enable register.
+        if eeprom {
+            f021::enable_eeprom_bank_sectors(sectors)?;
+        } else {
+            f021::enable_main_bank_sectors(sectors as u16)?;
+        }
+        Ok(())
+    }
+
+    fn set_otp_writable(&self, bank: FlashBank, writable: bool) {
+        let otpprotdis = 1 << (16 + bank as u32);
+        let fbac = f021::read_register(device::FBAC);
+        if writable {
+            f021::write_register(device::FBAC, fbac | otpprotdis);
+        } else {
+            f021::write_register(device::FBAC, fbac & !otpprotdis);
+        }
+    }
+
+    fn row_width(&self, eeprom: bool) -> usize {
+        let fcfg_bank = f021::read_register(device::FCFG_BANK);
+        let bits = if eeprom {
+            fcfg_bank >> 20
+        } else {
+            fcfg_bank >> 4
+        } & 0xfff;
+        // Every 64 bits of data come with 8 bits of ECC
+        bits as usize / 72 * 8
+    }
+
+    fn clear_status(&self) -> Result<(), Error> {
+        f021::issue_async_command(f021::FlashStateCommand::ClearStatus)?;
+        Ok(())
+    }
+
+    fn clear_more(&self) -> Result<(), Error> {
+        f021::issue_async_command(f021::FlashStateCommand::ClearMore)?;
+        Ok(())
+    }
+
+    fn erase_sector(&self, address: u32) -> Result<(), Error> {
+        f021::issue_async_command_with_address(
+            f021::FlashStateCommand::EraseSector,
+            address as *mut u32,
+        )?;
+        Ok(())
+    }
+
+    fn suspend_erase(&self) {
+        write_protected(|| {
+            let fsm_execute = f021::read_register(FSM_EXECUTE);
+            f021::write_register(
+                FSM_EXECUTE,
+                (fsm_execute & !FSM_EXECUTE_SUSPEND_NOW) | FSM_EXECUTE_SUSPEND,
+            );
+        });
+    }
+
+    fn resume_erase(&self) -> Result<(), Error> {
+        f021::issue_async_command(f021::FlashStateCommand::EraseResume)?;
+        Ok(())
+    }
+
+    fn program(
+        &self,
+        address: u32,
+        data: &[u8],
+        ecc: Option<&[u8]>,
+        mode: FlashProgrammingCommand,
+    ) -> Result<(), Error> {
+        f021::issue_programming_command(address as *mut u32, data, ecc, mode)?;
+        Ok(())
+    }
+
+    fn blank_check(&self, address: u32, size: u32) -> Result<(), FlashStatus> {
+        f021::blank_check(address, size)
+    }
+
+    fn blank_check_bytewise(&self, address: u32, size: u32) -> Result<(), FlashStatus> {
+        f021::blank_check_bytewise(address, size)
+    }
+
+    fn verify(&self, address: u32, data: &[u32]) -> Result<(), FlashStatus> {
+        f021::verify(address, data)
+    }
+
+    fn verify_bytewise(&self, address: u32, data: &[u8]) -> Result<(), FlashStatus> {
+        f021::verify_bytewise(address, data)
+    }
+
+    fn read(&self, address: u32, buffer: &mut [u8]) {
+        f021::read(address, buffer)
+    }
+
+    fn margin_read(&self, address: u32, buffer: &mut [u8], mode: ReadMode) -> Result<(), Error> {
+        f021::margin_read_bytewise(address, buffer, mode)
+    }
+
+    fn psa(&self, address: u32, words: u32) -> u32 {
+        f021::calculate_psa(address, words, 0, ReadMode::Normal)
+    }
+
+    fn fletcher32(&self, address: u32, size: u32) -> u32 {
+        // The API only works in whole 16-bit words
+        if address.is_multiple_of(2) && size.is_multiple_of(2) {
+            f021::calculate_fletcher_checksum(address, size / 2)
+        } else {
+            checksum::fletcher32(self, address, size)
+        }
+    }
+
+    fn read_without_ecc(&self, address: u32, buffer: &mut [u8]) {
+        f021::disable_ecc();
+        f021::read(address, buffer);
+        f021::enable_ecc();
+    }
+
+    fn fsm_status(&self) -> FsmStatusRegister {
+        f021::fsm_status()
+    }
+
+    fn flush(&self) {
+        f021::flush()
+    }
+}
@@ -0,0 +1,219 @@
+//! Checksums over flash, so that a host tool can check an image against its ELF
+//! file without reading all of it back, and the application header that a
+//! bootloader checks before starting the application.
+
+use crate::backend::FlashBackend;
+use crate::{APP_HEADER_ADDRESS, APP_HEADER_MAGIC, AlgorithmError, device};
+use flash_algorithm::ErrorCode;
+
+/// The number of bytes read from flash at a time.
+const CHUNK_SIZE: usize = 64;
+
+/// The kinds of checksum that `Checksum()` can calculate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChecksumKind {
+    /// CRC-32 calculated in software, which any host can reproduce.
+    Crc32 = 0,
+    /// The PSA signature calculated by the flash wrapper, which is faster but
+    /// specific to the F021 hardware.
+    Psa = 1,
+}
+
+impl TryFrom<u32> for ChecksumKind {
+    type Error = u32;
+
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Crc32),
+            1 => Ok(Self::Psa),
+            _ => Err(value),
+        }
+    }
+}
+
+/// Whether the `size` bytes at `address` are all in main flash, the EEPROM emulation
+/// bank or the ECC mirror. Reading anywhere else can raise a data abort.
+pub fn in_flash(address: u32, size: u32) -> bool {
+    let Some(end) = address.checked_add(size) else {
+        return false;
+    };
+    [
+        (0, device::MAIN_FLASH_SIZE),
+        (device::EEPROM_ADDRESS, device::EEPROM_SIZE),
+        (device::ECC_ADDRESS, device::ECC_SIZE),
+    ]
+    .iter()
+    .any(|&(start, length)| address >= start && end <= start + length)
+}
+
+/// The lookup table for the reflected CRC-32 polynomial.
+const CRC32_TABLE: [u32; 256] = crc32_table();
+
+const fn crc32_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// A running CRC-32, the same one as zlib's `crc32()`.
+pub struct Crc32(u32);
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Self(!0)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for &byte in data {
+            self.0 = (self.0 >> 8) ^ CRC32_TABLE[((self.0 ^ byte as u32) & 0xff) as usize];
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.0
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Read `size` bytes of flash at `address` a chunk at a time, passing each chunk to `f`.
+pub fn for_each_chunk(
+    backend: &impl FlashBackend,
+    address: u32,
+    size: u32,
+    mut f: impl FnMut(&[u8]),
+) {
+    let mut buffer = [0u8; CHUNK_SIZE];
+    let mut offset = 0;
+    while offset < size {
+        let length = ((size - offset) as usize).min(CHUNK_SIZE);
+        backend.read(address + offset, &mut buffer[..length]);
+        f(&buffer[..length]);
+        offset += length as u32;
+    }
+}
+
+/// The CRC-32 of `size` bytes of flash at `address`.
+pub fn crc32(backend: &impl FlashBackend, address: u32, size: u32) -> u32 {
+    let mut crc = Crc32::new();
+    for_each_chunk(backend, address, size, |chunk| crc.update(chunk));
+    crc.finish()
+}
+
+/// A running Fletcher-32 over 16-bit words in the device's byte order, with both
+/// sums starting at 0xffff. Every chunk but the last must be an even number of
+/// bytes, and the last is padded with a zero byte if it isn't.
+pub struct Fletcher32 {
+    sum1: u32,
+    sum2: u32,
+}
+
+impl Fletcher32 {
+    pub fn new() -> Self {
+        Self {
+            sum1: 0xffff,
+            sum2: 0xffff,
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        for word in data.chunks(2) {
+            let word = u16::from_ne_bytes([word[0], word.get(1).copied().unwrap_or(0)]);
+            self.sum1 += word as u32;
+            self.sum1 = (self.sum1 & 0xffff) + (self.sum1 >> 16);
+            self.sum2 += self.sum1;
+            self.sum2 = (self.sum2 & 0xffff) + (self.sum2 >> 16);
+        }
+    }
+
+    pub fn finish(&self) -> u32 {
+        (self.sum2 << 16) | self.sum1
+    }
+}
+
+impl Default for Fletcher32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The Fletcher-32 of `size` bytes of flash at `address`, calculated in software.
+/// `FlashBackend::fletcher32()` uses the F021 API instead where it can.
+pub fn fletcher32(backend: &impl FlashBackend, address: u32, size: u32) -> u32 {
+    let mut fletcher = Fletcher32::new();
+    for_each_chunk(backend, address, size, |chunk| fletcher.update(chunk));
+    fletcher.finish()
+}
+
+/// The application header that the bootloader checks before starting the
+/// application. Each field is a word in the device's byte order.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AppHeader {
+    /// `APP_HEADER_MAGIC`
+    pub magic: u32,
+    /// The address of the first byte covered by the checksum
+    pub start: u32,
+    /// The number of bytes covered by the checksum
+    pub length: u32,
+    /// The Fletcher-32 of the range
+    pub checksum: u32,
+}
+
+impl AppHeader {
+    /// The size of the header in flash.
+    pub const SIZE: usize = 16;
+
+    /// Read the header at `address`.
+    pub fn read(backend: &impl FlashBackend, address: u32) -> Self {
+        let mut buffer = [0u8; Self::SIZE];
+        backend.read(address, &mut buffer);
+        let word =
+            |index: usize| u32::from_ne_bytes(buffer[index * 4..index * 4 + 4].try_into().unwrap());
+        Self {
+            magic: word(0),
+            start: word(1),
+            length: word(2),
+            checksum: word(3),
+        }
+    }
+}
+
+/// Check the image described by the application header at `header_address`, or
+/// at `APP_HEADER_ADDRESS` if it is 0, in the same way as the bootloader does. A
+/// header outside of flash, or one describing a range outside of flash, counts as
+/// no header at all.
+pub fn validate_image(backend: &impl FlashBackend, header_address: u32) -> Result<(), ErrorCode> {
+    let header_address = match header_address {
+        0 => APP_HEADER_ADDRESS,
+        address => address,
+    };
+    if !in_flash(header_address, AppHeader::SIZE as u32) {
+        return Err(AlgorithmError::NoImageHeader.into());
+    }
+    let header = AppHeader::read(backend, header_address);
+    if header.magic != APP_HEADER_MAGIC || !in_flash(header.start, header.length) {
+        return Err(AlgorithmError::NoImageHeader.into());
+    }
+    if backend.fletcher32(header.start, header.length) != header.checksum {
+        return Err(AlgorithmError::ChecksumMismatch.into());
+    }
+    Ok(())
+}
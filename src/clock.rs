@@ -0,0 +1,55 @@
+//! Working out HCLK from the system module, for when probe-rs doesn't pass a clock.
+//! The flash API times program and erase pulses from HCLK, so guessing wrong makes
+//! them too short or too long.
+
+use crate::device::{self, CSDIS, GHVSRC, PLLCTL1, PLLCTL2, PLLCTL3};
+use crate::f021;
+
+/// The GHVSRC clock sources that can be worked out. The others are EXTCLKIN1/2,
+/// which could be anything, and the LPOs, which are too imprecise to time pulses.
+const SOURCE_OSCIN: u32 = 0;
+const SOURCE_PLL1: u32 = 1;
+const SOURCE_PLL2: u32 = 6;
+
+/// Return the current HCLK in MHz, rounded up, or `None` if it can't be worked out.
+pub fn hclk_mhz() -> Option<u32> {
+    let source = f021::read_register(GHVSRC) & 15;
+
+    // A disabled source can't be the one that's running, so the registers are stale
+    if f021::read_register(CSDIS) & (1 << source) != 0 {
+        return None;
+    }
+
+    let gclk_khz = match source {
+        SOURCE_OSCIN => device::OSCIN_KHZ,
+        SOURCE_PLL1 => {
+            let pllctl1 = f021::read_register(PLLCTL1);
+            let odpll = (f021::read_register(PLLCTL2) >> 9) & 7;
+            pll_khz(pllctl1, odpll)
+        }
+        SOURCE_PLL2 => {
+            let pllctl3 = f021::read_register(PLLCTL3);
+            pll_khz(pllctl3, pllctl3 >> 29)
+        }
+        _ => return None,
+    };
+
+    let hclkr = match device::HCLKCNTL {
+        Some(hclkcntl) => f021::read_register(hclkcntl) & 3,
+        None => 0,
+    };
+    let hclk_khz = gclk_khz / (hclkr + 1);
+    Some(hclk_khz.div_ceil(1000))
+}
+
+/// The output of a PLL whose REFCLKDIV, PLLMUL and PLLDIV are in `pllctl` in the
+/// layout of PLLCTL1 and PLLCTL3, and whose output divider is `odpll`.
+fn pll_khz(pllctl: u32, odpll: u32) -> u32 {
+    let refclkdiv = (pllctl >> 16) & 63;
+    let pllmul = pllctl & 0xffff;
+    let plldiv = (pllctl >> 24) & 31;
+
+    // PLLMUL holds NF - 1 in 8.8 fixed point
+    let vco = device::OSCIN_KHZ as u64 * (pllmul as u64 + 256) / 256 / (refclkdiv as u64 + 1);
+    (vco / (odpll as u64 + 1) / (plldiv as u64 + 1)) as u32
+}
@@ -0,0 +1,402 @@
+//! TMS570LS31x and RM48x: 3 MB of flash in banks 0 and 1, using the original
+//! F021 flash controller, plus a 64 KB EEPROM emulation bank at 0xF0200000.
+
+use crate::wait_states::WaitStateLimits;
+
+// A collection of registers
+
+/// Called FBFALLBACK on this family
+pub const FBPWRMODE: *mut u32 = 0xfff8_7040 as *mut u32;
+/// The EWAIT field of EEPROM_CONFIG
+pub const EWAIT: *mut u32 = 0xfff8_72b8 as *mut u32;
+pub const FSM_WR_ENA: *mut u32 = 0xfff8_7288 as *mut u32;
+/// Contains SUSPEND_NOW, which suspends the FSM
+pub const FSM_EXECUTE: *mut u32 = 0xfff8_72b4 as *mut u32;
+pub const FRDCNTL: *mut u32 = 0xfff8_7000 as *mut u32;
+/// Contains one OTPPROTDIS bit per bank, starting at bit 16
+pub const FBAC: *mut u32 = 0xfff8_703c as *mut u32;
+/// The active bank is in the low three bits
+pub const FMAC: *mut u32 = 0xfff8_7050 as *mut u32;
+/// The row width of the main banks in bits 15:4, and of the EEPROM emulation bank
+/// in bits 31:20, both in bits including the ECC
+pub const FCFG_BANK: *mut u32 = 0xfff8_7400 as *mut u32;
+
+// System module registers, which are used to work out HCLK
+
+/// Selects the clock source of GCLK1, HCLK and VCLK in the low four bits
+pub const GHVSRC: *mut u32 = 0xffff_ff48 as *mut u32;
+/// One bit per clock source, which is set if the source is disabled
+pub const CSDIS: *mut u32 = 0xffff_ff30 as *mut u32;
+/// PLL1 REFCLKDIV, PLLMUL and PLLDIV
+pub const PLLCTL1: *mut u32 = 0xffff_ff70 as *mut u32;
+/// PLL1 ODPLL
+pub const PLLCTL2: *mut u32 = 0xffff_ff74 as *mut u32;
+/// PLL2 ODPLL2, PLLDIV2, REFCLKDIV2 and PLLMUL2
+pub const PLLCTL3: *mut u32 = 0xffff_e100 as *mut u32;
+/// HCLK always runs at GCLK1 on this family
+pub const HCLKCNTL: Option<*mut u32> = None;
+
+/// The OSCIN crystal in kHz, which is 16 MHz on the HDK and Launch-XL
+pub const OSCIN_KHZ: u32 = 16_000;
+
+/// Flash timing from the datasheet
+pub const WAIT_STATE_LIMITS: WaitStateLimits = WaitStateLimits {
+    max_hclk_mhz: 180,
+    rwait_step_mhz: 45,
+    ewait_step_mhz: 15,
+};
+
+/// Worst-case FSM times from the datasheet, rounded up. An operation that takes
+/// longer than this is assumed to have wedged the FSM.
+pub const PROGRAM_TIMEOUT_US: u32 = 1_000;
+pub const SECTOR_ERASE_TIMEOUT_US: u32 = 4_000_000;
+pub const BANK_ERASE_TIMEOUT_US: u32 = 14_000_000;
+
+/// The number of data bytes in one row of a main flash bank (144 bits with ECC)
+/// and of the EEPROM emulation bank (72 bits with ECC). A programming command can
+/// take at most one row. The widths themselves come from `FCFG_BANK`, and these
+/// are the most that the algorithm allows for.
+pub const FLEP_ROW_WIDTH: usize = 16;
+pub const FLEE_ROW_WIDTH: usize = 8;
+
+/// The size of main flash, which starts at 0
+pub const MAIN_FLASH_SIZE: u32 = 0x30_0000;
+
+/// Start of the EEPROM emulation bank
+pub const EEPROM_ADDRESS: u32 = 0xf020_0000;
+pub const EEPROM_SIZE: u32 = 0x1_0000;
+
+/// Start of the customer OTP. Each bank has its own window, with bank 7 at 0xF000E000.
+pub const OTP_ADDRESS: u32 = 0xf000_0000;
+pub const OTP_BANK_SIZE: u32 = 0x2000;
+pub const OTP_WINDOW_SIZE: u32 = 8 * OTP_BANK_SIZE;
+
+/// Start of the ECC mirror of main flash, with one ECC byte for each 64-bit word
+pub const ECC_ADDRESS: u32 = 0xf040_0000;
+pub const ECC_SIZE: u32 = 0x6_0000;
+
+/// Value written to FBFALLBACK to put every bank into active mode
+pub const FBPWRMODE_ALL_ACTIVE: u32 = 0x0505_ffff;
+
+/// Bits to set in FRDCNTL alongside RWAIT: ENPIPE
+pub const FRDCNTL_ENABLES: u32 = 0b1;
+
+#[cfg(not(any(feature = "eeprom", feature = "otp", feature = "ecc")))]
+flash_algorithm::algorithm!(crate::Algorithm, {
+    device_name: "f021",
+    device_type: DeviceType::Onchip,
+    flash_address: 0,
+    flash_size: (3*1024*1024),
+    // Note that the page size is effectively the same as
+    // the sector size due to how the API works. The value
+    // presented here is simply the smallest sector size.
+    page_size: (32*1024),
+    empty_value: 0xFFu8,
+    program_time_out: 2000,
+    // `EraseAll()` erases the 27 sectors one at a time, and each of them
+    // can take up to `SECTOR_ERASE_TIMEOUT_US`
+    erase_time_out: (27*SECTOR_ERASE_TIMEOUT_US/1000),
+    // This table is only exported to probe-rs. The algorithm itself looks up
+    // sectors at runtime using `f021::bank_sectors()`.
+    sectors: [
+        // Bank 0
+        {
+            size: 0x8000u32,
+            address: 0x0u32,
+        },
+        {
+            size: 0x8000u32,
+            address: 0x8000u32,
+        },
+        {
+            size: 0x8000u32,
+            address: 0x10000u32,
+        },
+        {
+            size: 0x8000u32,
+            address: 0x18000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x20000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x40000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x60000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x80000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0xa0000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0xc0000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0xe0000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x100000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x120000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x140000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x160000u32,
+        },
+        // Bank 1
+        {
+            size: 0x20000u32,
+            address: 0x180000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x1a0000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x1c0000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x1e0000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x200000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x220000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x240000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x260000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x280000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x2a0000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x2c0000u32,
+        },
+        {
+            size: 0x20000u32,
+            address: 0x2e0000u32,
+        } // Must skip final `,`
+    ]
+});
+
+#[cfg(feature = "eeprom")]
+flash_algorithm::algorithm!(crate::Algorithm, {
+    device_name: "f021-eeprom",
+    device_type: DeviceType::Onchip,
+    flash_address: 0xf020_0000,
+    flash_size: (64*1024),
+    page_size: (16*1024),
+    empty_value: 0xFFu8,
+    program_time_out: 2000,
+    // `EraseAll()` erases the 4 sectors one at a time, and each of them
+    // can take up to `SECTOR_ERASE_TIMEOUT_US`
+    erase_time_out: (4*SECTOR_ERASE_TIMEOUT_US/1000),
+    // This table is only exported to probe-rs. The algorithm itself looks up
+    // sectors at runtime using `f021::bank_sectors()`.
+    sectors: [
+        // Bank 7
+        {
+            size: 0x4000u32,
+            address: 0xf0200000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0204000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0208000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf020c000u32,
+        } // Must skip final `,`
+    ]
+});
+
+#[cfg(feature = "otp")]
+flash_algorithm::algorithm!(crate::Algorithm, {
+    device_name: "f021-otp",
+    device_type: DeviceType::Onchip,
+    flash_address: 0xf000_0000,
+    flash_size: (64*1024),
+    page_size: (1024),
+    empty_value: 0xFFu8,
+    program_time_out: 2000,
+    erase_time_out: 14000,
+    // The customer OTP of each bank. It can't be erased, so erasing it does nothing.
+    sectors: [
+        // Bank 0
+        {
+            size: 0x2000u32,
+            address: 0xf0000000u32,
+        },
+        // Bank 1
+        {
+            size: 0x2000u32,
+            address: 0xf0002000u32,
+        },
+        // Bank 7
+        {
+            size: 0x2000u32,
+            address: 0xf000e000u32,
+        } // Must skip final `,`
+    ]
+});
+
+#[cfg(feature = "ecc")]
+flash_algorithm::algorithm!(crate::Algorithm, {
+    device_name: "f021-ecc",
+    device_type: DeviceType::Onchip,
+    flash_address: 0xf040_0000,
+    flash_size: (3*1024*1024/8),
+    page_size: (32*1024/8),
+    empty_value: 0xFFu8,
+    program_time_out: 2000,
+    erase_time_out: 14000,
+    // The ECC mirror of the main flash sectors, with one byte for every 64-bit
+    // word. The ECC is erased along with its data, so erasing it does nothing.
+    sectors: [
+        {
+            size: 0x1000u32,
+            address: 0xf0400000u32,
+        },
+        {
+            size: 0x1000u32,
+            address: 0xf0401000u32,
+        },
+        {
+            size: 0x1000u32,
+            address: 0xf0402000u32,
+        },
+        {
+            size: 0x1000u32,
+            address: 0xf0403000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0404000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0408000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf040c000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0410000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0414000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0418000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf041c000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0420000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0424000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0428000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf042c000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0430000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0434000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0438000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf043c000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0440000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0444000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0448000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf044c000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0450000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0454000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf0458000u32,
+        },
+        {
+            size: 0x4000u32,
+            address: 0xf045c000u32,
+        } // Must skip final `,`
+    ]
+});
@@ -0,0 +1,21 @@
+//! Device profiles. The TMS570LC43x is used by default, and the TMS570LS31x/RM48x
+//! family is selected with the `ls3137` feature.
+
+#[cfg(not(feature = "ls3137"))]
+mod lc4357;
+#[cfg(not(feature = "ls3137"))]
+pub use lc4357::*;
+
+#[cfg(feature = "ls3137")]
+mod ls3137;
+#[cfg(feature = "ls3137")]
+pub use ls3137::*;
+
+#[cfg(any(
+    all(feature = "eeprom", feature = "otp"),
+    all(feature = "eeprom", feature = "ecc"),
+    all(feature = "otp", feature = "ecc"),
+))]
+compile_error!(
+    "Each build exports one region, so only one of `eeprom`, `otp` and `ecc` can be enabled"
+);
@@ -1,13 +1,219 @@
 #![allow(dead_code)]
 
+#[cfg(feature = "sim")]
+pub mod sim;
 mod sys;
 
-#[derive(Debug, PartialEq)]
-pub enum FsmStatus {
-    Ready,
-    Busy,
+/// A decoded copy of the FMSTAT register.
+#[derive(Clone, Copy, PartialEq)]
+pub struct FsmStatusRegister(u32);
+
+impl FsmStatusRegister {
+    const NAMES: [&'static str; 18] = [
+        "SLOCK", "PSUSP", "ESUSP", "VOLTSTAT", "CSTAT", "INVDAT", "PGM", "ERS", "BUSY", "CV", "EV",
+        "PCV", "PGV", "DBF", "ILA", "RVF", "RDVER", "RVSUSP",
+    ];
+
+    fn bit(&self, bit: u32) -> bool {
+        self.0 & (1 << bit) != 0
+    }
+
+    pub fn raw(&self) -> u32 {
+        self.0
+    }
+
+    /// The operation targeted a sector that is locked
+    pub fn slock(&self) -> bool {
+        self.bit(0)
+    }
+
+    /// A program operation is suspended
+    pub fn psusp(&self) -> bool {
+        self.bit(1)
+    }
+
+    /// An erase operation is suspended
+    pub fn esusp(&self) -> bool {
+        self.bit(2)
+    }
+
+    /// The core voltage generator reported an error
+    pub fn voltstat(&self) -> bool {
+        self.bit(3)
+    }
+
+    /// The command failed
+    pub fn cstat(&self) -> bool {
+        self.bit(4)
+    }
+
+    /// An attempt was made to program a `1` into a bit that is `0`
+    pub fn invdat(&self) -> bool {
+        self.bit(5)
+    }
+
+    /// A program operation is in progress
+    pub fn pgm(&self) -> bool {
+        self.bit(6)
+    }
+
+    /// An erase operation is in progress
+    pub fn ers(&self) -> bool {
+        self.bit(7)
+    }
+
+    /// The FSM is busy
+    pub fn busy(&self) -> bool {
+        self.bit(8)
+    }
+
+    /// Compact verify failed
+    pub fn cv(&self) -> bool {
+        self.bit(9)
+    }
+
+    /// Erase verify failed
+    pub fn ev(&self) -> bool {
+        self.bit(10)
+    }
+
+    /// Precondition verify failed
+    pub fn pcv(&self) -> bool {
+        self.bit(11)
+    }
+
+    /// Program verify failed
+    pub fn pgv(&self) -> bool {
+        self.bit(12)
+    }
+
+    /// Disturbance test failed
+    pub fn dbf(&self) -> bool {
+        self.bit(13)
+    }
+
+    /// The operation targeted an illegal address
+    pub fn ila(&self) -> bool {
+        self.bit(14)
+    }
+
+    /// Read verify failed
+    pub fn rvf(&self) -> bool {
+        self.bit(15)
+    }
+
+    /// A read verify command is in progress
+    pub fn rdver(&self) -> bool {
+        self.bit(16)
+    }
+
+    /// A read verify operation is suspended
+    pub fn rvsusp(&self) -> bool {
+        self.bit(17)
+    }
+
+    /// Return the failure reported by the most recent FSM operation, if any.
+    /// More specific failures are reported ahead of the generic `CSTAT` bit.
+    pub fn error(&self) -> Option<FsmError> {
+        if self.ila() {
+            Some(FsmError::IllegalAddress)
+        } else if self.slock() {
+            Some(FsmError::SectorLocked)
+        } else if self.voltstat() {
+            Some(FsmError::VoltageError)
+        } else if self.invdat() {
+            Some(FsmError::InvalidData)
+        } else if self.pgv() {
+            Some(FsmError::ProgramVerify)
+        } else if self.ev() {
+            Some(FsmError::EraseVerify)
+        } else if self.pcv() {
+            Some(FsmError::PreconditionVerify)
+        } else if self.cv() {
+            Some(FsmError::CompactVerify)
+        } else if self.dbf() {
+            Some(FsmError::DisturbanceTest)
+        } else if self.rvf() {
+            Some(FsmError::ReadVerify)
+        } else if self.cstat() {
+            Some(FsmError::CommandFailed)
+        } else {
+            None
+        }
+    }
 }
 
+impl From<u32> for FsmStatusRegister {
+    fn from(value: u32) -> Self {
+        FsmStatusRegister(value)
+    }
+}
+
+impl core::fmt::Debug for FsmStatusRegister {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "FsmStatusRegister({:08x}:", self.0)?;
+        for (bit, name) in Self::NAMES.iter().enumerate() {
+            if self.bit(bit as u32) {
+                write!(f, " {}", name)?;
+            }
+        }
+        write!(f, ")")
+    }
+}
+
+/// A failure reported by the FSM in FMSTAT after an operation completes.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FsmError {
+    /// The operation targeted an illegal address (ILA)
+    IllegalAddress = 1,
+    /// The operation targeted a locked sector (SLOCK)
+    SectorLocked = 2,
+    /// The core voltage generator reported an error (VOLTSTAT)
+    VoltageError = 3,
+    /// Attempted to program a `1` into a bit that is already `0` (INVDAT)
+    InvalidData = 4,
+    /// Program verify failed (PGV)
+    ProgramVerify = 5,
+    /// Erase verify failed (EV)
+    EraseVerify = 6,
+    /// Precondition verify failed (PCV)
+    PreconditionVerify = 7,
+    /// Compact verify failed (CV)
+    CompactVerify = 8,
+    /// Disturbance test failed (DBF)
+    DisturbanceTest = 9,
+    /// Read verify failed (RVF)
+    ReadVerify = 10,
+    /// The command failed for an unspecified reason (CSTAT)
+    CommandFailed = 11,
+}
+
+impl core::fmt::Display for FsmError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            FsmError::IllegalAddress => write!(f, "illegal address"),
+            FsmError::SectorLocked => write!(f, "sector locked"),
+            FsmError::VoltageError => write!(f, "core voltage generator error"),
+            FsmError::InvalidData => write!(f, "attempted to program a 1 over a 0"),
+            FsmError::ProgramVerify => write!(f, "program verify failed"),
+            FsmError::EraseVerify => write!(f, "erase verify failed"),
+            FsmError::PreconditionVerify => write!(f, "precondition verify failed"),
+            FsmError::CompactVerify => write!(f, "compact verify failed"),
+            FsmError::DisturbanceTest => write!(f, "disturbance test failed"),
+            FsmError::ReadVerify => write!(f, "read verify failed"),
+            FsmError::CommandFailed => write!(f, "command failed"),
+        }
+    }
+}
+
+impl From<FsmError> for u32 {
+    fn from(val: FsmError) -> Self {
+        val as u32
+    }
+}
+
+impl core::error::Error for FsmError {}
+
 /// This is used to indicate what F021 Bank Technology the bank is
 #[derive(Debug, PartialEq, Default)]
 pub enum FlashBankTech {
@@ -55,6 +261,10 @@ impl TryFrom<u8> for FlashBankTech {
     }
 }
 
+/// The status returned when a blank check or verify fails. For a blank check,
+/// `comparison_data` is the erased value. For a verify, `non_blank_address` is
+/// the first mismatched address, `non_blank_data` is the value read back from
+/// flash, and `comparison_data` is the expected value.
 #[derive(Debug)]
 pub struct FlashStatus {
     pub non_blank_address: u32,
@@ -63,6 +273,57 @@ pub struct FlashStatus {
     pub read_mode: u32,
 }
 
+impl From<sys::Fapi_FlashStatusWordType> for FlashStatus {
+    fn from(val: sys::Fapi_FlashStatusWordType) -> Self {
+        FlashStatus {
+            non_blank_address: val.au32StatusWord[0],
+            non_blank_data: val.au32StatusWord[1],
+            comparison_data: val.au32StatusWord[2],
+            read_mode: val.au32StatusWord[3],
+        }
+    }
+}
+
+/// The modes that flash can be read in, as used for margin reads and reported in
+/// `FlashStatus::read_mode`. Margin reads move the sense threshold so that cells
+/// which are only just erased read as programmed under margin 0, and cells which
+/// are only just programmed read as erased under margin 1.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum ReadMode {
+    Normal = 0,
+    Margin0 = 1,
+    Margin1 = 2,
+}
+
+impl From<ReadMode> for sys::Fapi_FlashReadMarginModeType {
+    fn from(val: ReadMode) -> Self {
+        match val {
+            ReadMode::Normal => sys::Fapi_FlashReadMarginModeType::Fapi_NormalRead,
+            ReadMode::Margin0 => sys::Fapi_FlashReadMarginModeType::Fapi_RM0,
+            ReadMode::Margin1 => sys::Fapi_FlashReadMarginModeType::Fapi_RM1,
+        }
+    }
+}
+
+impl TryFrom<u32> for ReadMode {
+    type Error = u32;
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(ReadMode::Normal),
+            1 => Ok(ReadMode::Margin0),
+            2 => Ok(ReadMode::Margin1),
+            other => Err(other),
+        }
+    }
+}
+
+impl FlashStatus {
+    /// The mode that the failing read was made in, if it is a known one.
+    pub fn read_mode(&self) -> Option<ReadMode> {
+        self.read_mode.try_into().ok()
+    }
+}
+
 #[derive(Default)]
 pub struct FlashBankSectors {
     pub flash_bank_tech: FlashBankTech,
@@ -460,28 +721,11 @@ pub fn issue_async_command(command: FlashStateCommand) -> Result<Status, Error>
 }
 
 pub fn disable_ecc() {
-    unsafe {
-        core::arch::asm!(
-            "
-                mrc   p15, #0x00, r0,         c1, c0,  #0x01
-                bic   r0,  r0,    #0x02000000
-                mcr   p15, #0x00, r0,         c1, c0,  #0x01
-            "
-        );
-    }
+    unsafe { sys::disable_ecc() }
 }
 
 pub fn enable_ecc() {
-    unsafe {
-        core::arch::asm!(
-            "
-                mrc   p15, #0x00, r0,         c1, c0,  #0x01
-                orr   r0,  r0,    #0x02000000
-                dmb
-                mcr   p15, #0x00, r0,         c1, c0,  #0x01
-            "
-        )
-    }
+    unsafe { sys::enable_ecc() }
 }
 
 pub fn issue_async_command_with_address(
@@ -491,6 +735,10 @@ pub fn issue_async_command_with_address(
     unsafe { sys::Fapi_issueAsyncCommandWithAddress(command.into(), start_address) }.try_into()
 }
 
+/// Program `data_buffer` to `start_address`. Both buffers are in flash byte order, so
+/// `data_buffer[0]` always lands at `start_address` regardless of the endianness of
+/// the CPU. `ecc_buffer`, if present, contains one ECC byte for each 64-bit word of
+/// `data_buffer`, in address order.
 pub fn issue_programming_command(
     start_address: *mut u32,
     data_buffer: &[u8],
@@ -585,28 +833,54 @@ pub fn device_info() -> DeviceInfo {
     }
 }
 
-pub fn fsm_running() -> FsmStatus {
-    let fmstat = unsafe { sys::FMSTAT_ADDRESS.read_volatile() };
-    if fmstat & (1 << 8) == 0 {
-        FsmStatus::Ready
-    } else {
-        FsmStatus::Busy
-    }
+/// Read the FMSTAT register, which reports the result of the most recent FSM operation.
+pub fn fsm_status() -> FsmStatusRegister {
+    read_register(sys::FMSTAT_ADDRESS).into()
 }
 
-
 /// Invalidate D$ and I$
 pub fn invalidate_caches() {
-    unsafe {
-        core::arch::asm!("
-            mov   r0,#0
-            dsb
-            mcr   p15, #0, r0, c15, c5, #0 // dcache
-            mcr   p15, #0, r0, c7, c5, #0 // icache
-            dsb
-        ", out("r0") _);
-    }
+    unsafe { sys::invalidate_caches() }
+}
+
+/// The PMU registers that `enable_cycle_counter()` changes.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CycleCounterConfig {
+    pub pmcr: u32,
+    pub pmcntenset: u32,
 }
+
+/// Read the PMU registers that `enable_cycle_counter()` changes.
+pub fn cycle_counter_config() -> CycleCounterConfig {
+    let (pmcr, pmcntenset) = unsafe { sys::read_cycle_counter_config() };
+    CycleCounterConfig { pmcr, pmcntenset }
+}
+
+/// Put the PMU back the way `cycle_counter_config()` found it.
+pub fn restore_cycle_counter(config: CycleCounterConfig) {
+    unsafe { sys::write_cycle_counter_config(config.pmcr, config.pmcntenset) }
+}
+
+/// Start the PMU cycle counter, if it isn't already running.
+pub fn enable_cycle_counter() {
+    unsafe { sys::enable_cycle_counter() }
+}
+
+/// The PMU cycle counter, which wraps around every 2^32 CPU cycles.
+pub fn cycle_counter() -> u32 {
+    unsafe { sys::read_cycle_counter() }
+}
+
+/// Read a flash controller register, such as one of the registers in the device profile.
+pub fn read_register(register: *mut u32) -> u32 {
+    unsafe { sys::read_register(register) }
+}
+
+/// Write a flash controller register, such as one of the registers in the device profile.
+pub fn write_register(register: *mut u32, value: u32) {
+    unsafe { sys::write_register(register, value) }
+}
+
 pub fn blank_check(address: u32, size: u32) -> Result<(), FlashStatus> {
     let mut flash_status = sys::Fapi_FlashStatusWordType::default();
     invalidate_caches();
@@ -616,12 +890,7 @@ pub fn blank_check(address: u32, size: u32) -> Result<(), FlashStatus> {
     }
     .try_into();
     if status.is_err() {
-        Err(FlashStatus {
-            non_blank_address: flash_status.au32StatusWord[0],
-            non_blank_data: flash_status.au32StatusWord[1],
-            comparison_data: flash_status.au32StatusWord[2],
-            read_mode: flash_status.au32StatusWord[3],
-        })
+        Err(flash_status.into())
     } else {
         Ok(())
     }
@@ -636,17 +905,139 @@ pub fn blank_check_bytewise(address: u32, size: u32) -> Result<(), FlashStatus>
     }
     .try_into();
     if status.is_err() {
-        Err(FlashStatus {
-            non_blank_address: flash_status.au32StatusWord[0],
-            non_blank_data: flash_status.au32StatusWord[1],
-            comparison_data: flash_status.au32StatusWord[2],
-            read_mode: flash_status.au32StatusWord[3],
-        })
+        Err(flash_status.into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Compare the flash at `address` against `data`, one 32-bit word at a time.
+pub fn verify(address: u32, data: &[u32]) -> Result<(), FlashStatus> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let mut flash_status = sys::Fapi_FlashStatusWordType::default();
+    invalidate_caches();
+    // Note: The length is in units of 32-bits.
+    let status: Result<Status, Error> = unsafe {
+        sys::Fapi_doVerify(
+            address as *const u32,
+            data.len() as u32,
+            data.as_ptr(),
+            &mut flash_status as *mut _,
+        )
+    }
+    .try_into();
+    if status.is_err() {
+        Err(flash_status.into())
     } else {
         Ok(())
     }
 }
 
+/// Compare the flash at `address` against `data`, one byte at a time. Neither
+/// `address` nor `data` need to be aligned.
+pub fn verify_bytewise(address: u32, data: &[u8]) -> Result<(), FlashStatus> {
+    if data.is_empty() {
+        return Ok(());
+    }
+    let mut flash_status = sys::Fapi_FlashStatusWordType::default();
+    invalidate_caches();
+    let status: Result<Status, Error> = unsafe {
+        sys::Fapi_doVerifyByByte(
+            address as *const u8,
+            data.len() as u32,
+            data.as_ptr(),
+            &mut flash_status as *mut _,
+        )
+    }
+    .try_into();
+    if status.is_err() {
+        Err(flash_status.into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Read `buffer.len()` 32-bit words of flash starting at `address` in `mode`.
+pub fn margin_read(address: u32, buffer: &mut [u32], mode: ReadMode) -> Result<(), Error> {
+    if buffer.is_empty() {
+        return Ok(());
+    }
+    invalidate_caches();
+    // Note: The length is in units of 32-bits.
+    let status: Result<Status, Error> = unsafe {
+        sys::Fapi_doMarginRead(
+            address as *const u32,
+            buffer.as_mut_ptr(),
+            buffer.len() as u32,
+            mode.into(),
+        )
+    }
+    .try_into();
+    status.map(|_| ())
+}
+
+/// Read `buffer.len()` bytes of flash starting at `address` in `mode`. Neither
+/// `address` nor `buffer` need to be aligned.
+pub fn margin_read_bytewise(address: u32, buffer: &mut [u8], mode: ReadMode) -> Result<(), Error> {
+    if buffer.is_empty() {
+        return Ok(());
+    }
+    invalidate_caches();
+    let status: Result<Status, Error> = unsafe {
+        sys::Fapi_doMarginReadByByte(
+            address as *const u8,
+            buffer.as_mut_ptr(),
+            buffer.len() as u32,
+            mode.into(),
+        )
+    }
+    .try_into();
+    status.map(|_| ())
+}
+
+/// Calculate the Fletcher-32 checksum of `length` 16-bit words of flash at `address`.
+pub fn calculate_fletcher_checksum(address: u32, length: u32) -> u32 {
+    invalidate_caches();
+    unsafe { sys::Fapi_calculateFletcherChecksum(address, length) }
+}
+
+/// Calculate the PSA signature of `length` 32-bit words of flash at `address`, read
+/// in `mode`. The signature is calculated by the flash wrapper's own MISR, starting
+/// from `seed`.
+pub fn calculate_psa(address: u32, length: u32, seed: u32, mode: ReadMode) -> u32 {
+    invalidate_caches();
+    unsafe { sys::Fapi_calculatePsa(address as *const u32, length, seed, mode.into()) }
+}
+
+/// Check that the PSA signature of `length` 32-bit words of flash at `address`
+/// is `psa`, reading the flash under both read margins.
+pub fn psa_verify(address: u32, length: u32, psa: u32) -> Result<(), FlashStatus> {
+    let mut flash_status = sys::Fapi_FlashStatusWordType::default();
+    invalidate_caches();
+    let status: Result<Status, Error> = unsafe {
+        sys::Fapi_doPsaVerify(
+            address as *const u32,
+            length,
+            psa,
+            &mut flash_status as *mut _,
+        )
+    }
+    .try_into();
+    if status.is_err() {
+        Err(flash_status.into())
+    } else {
+        Ok(())
+    }
+}
+
+/// Read `buffer.len()` bytes of flash starting at `address`.
+pub fn read(address: u32, buffer: &mut [u8]) {
+    invalidate_caches();
+    unsafe { sys::read_flash(address, buffer) }
+}
+
 pub fn flush() {
     unsafe { sys::Fapi_flushPipeline() }
 }
@@ -0,0 +1,967 @@
+//! A software model of the F021 Flash API, used in place of the TI library when the
+//! `sim` feature is enabled. The functions here have the same signatures as those in
+//! `sys`, so everything above `sys` runs unmodified on the host.
+//!
+//! The model covers the parts of the flash controller that the algorithm relies on:
+//! the bank and sector layout of the selected device, erasing to `0xff`, programming
+//! that can only clear bits, the ECC bits of each 64-bit word, the customer OTP, the
+//! sector enables, and an FSM that stays busy for a few reads of FMSTAT before
+//! reporting its result there. Each thread gets its own device, so tests can run in
+//! parallel.
+//!
+//! The ECC generated here is a simple checksum rather than the real SECDED code.
+
+#![allow(non_snake_case)]
+
+extern crate std;
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::vec;
+use std::vec::Vec;
+
+use super::sys::{
+    FMSTAT_ADDRESS, Fapi_DeviceInfoType, Fapi_FlashBankSectorsType, Fapi_FlashBankType,
+    Fapi_FlashProgrammingCommandsType, Fapi_FlashReadMarginModeType, Fapi_FlashStateCommandsType,
+    Fapi_FlashStatusWordType, Fapi_LibraryInfoType,
+};
+use crate::device;
+
+// Return values from the API, as in `Fapi_StatusType`
+const SUCCESS: u32 = 0;
+const FSM_BUSY: u32 = 1;
+const ERROR_FAIL: u32 = 3;
+const ERROR_INVALID_COMMAND: u32 = 5;
+const ERROR_INVALID_HCLK_VALUE: u32 = 8;
+const ERROR_INVALID_BANK: u32 = 9;
+const ERROR_INVALID_ADDRESS: u32 = 10;
+const ERROR_INCORRECT_DATA_BUFFER_LENGTH: u32 = 12;
+const ERROR_INCORRECT_ECC_BUFFER_LENGTH: u32 = 13;
+const ERROR_DATA_ECC_BUFFER_LENGTH_MISMATCH: u32 = 14;
+
+// Bits in FMSTAT
+const FMSTAT_SLOCK: u32 = 1 << 0;
+const FMSTAT_ESUSP: u32 = 1 << 2;
+const FMSTAT_CSTAT: u32 = 1 << 4;
+const FMSTAT_INVDAT: u32 = 1 << 5;
+const FMSTAT_PGM: u32 = 1 << 6;
+const FMSTAT_ERS: u32 = 1 << 7;
+const FMSTAT_BUSY: u32 = 1 << 8;
+const FMSTAT_ILA: u32 = 1 << 14;
+
+/// The number of FMSTAT reads that a program operation stays busy for
+const PROGRAM_BUSY_READS: u32 = 2;
+/// The number of FMSTAT reads that an erase operation stays busy for
+const ERASE_BUSY_READS: u32 = 8;
+/// The value of SUSPEND_NOW in FSM_EXECUTE that suspends the FSM
+const SUSPEND_NOW: u32 = 0x5;
+/// The value of FSMEXECUTE in FSM_EXECUTE that starts the command in FSM_COMMAND
+const FSMEXECUTE_START: u32 = 0x15;
+/// The reset value of FSM_EXECUTE, with neither field active
+const FSM_EXECUTE_RESET: u32 = 0x000a_000a;
+/// The number of cycles that pass between two reads of the cycle counter
+const CYCLES_PER_READ: u32 = 1000;
+
+/// Technology of a bank, as in `Fapi_FlashBankTechType`
+const FLEP: u8 = 0;
+const FLEE: u8 = 1;
+
+/// A bank of the simulated device.
+struct BankLayout {
+    bank: u8,
+    tech: u8,
+    start: u32,
+    /// Sector sizes in KB
+    sectors: &'static [u16],
+    /// The number of data bytes in one row of the bank, as FCFG_BANK reports it
+    /// out of reset
+    width: usize,
+}
+
+#[cfg(not(feature = "ls3137"))]
+const BANKS: &[BankLayout] = &[
+    BankLayout {
+        bank: 0,
+        tech: FLEP,
+        start: 0x0000_0000,
+        sectors: &[
+            16, 16, 16, 16, 16, 16, 32, 128, 128, 128, 256, 256, 256, 256, 256, 256,
+        ],
+        width: 32,
+    },
+    BankLayout {
+        bank: 1,
+        tech: FLEP,
+        start: 0x0020_0000,
+        sectors: &[128; 16],
+        width: 32,
+    },
+    BankLayout {
+        bank: 7,
+        tech: FLEE,
+        start: 0xf020_0000,
+        sectors: &[4; 32],
+        width: 8,
+    },
+];
+
+#[cfg(feature = "ls3137")]
+const BANKS: &[BankLayout] = &[
+    BankLayout {
+        bank: 0,
+        tech: FLEP,
+        start: 0x0000_0000,
+        sectors: &[
+            32, 32, 32, 32, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128, 128,
+        ],
+        width: 16,
+    },
+    BankLayout {
+        bank: 1,
+        tech: FLEP,
+        start: 0x0018_0000,
+        sectors: &[128; 12],
+        width: 16,
+    },
+    BankLayout {
+        bank: 7,
+        tech: FLEE,
+        start: 0xf020_0000,
+        sectors: &[16; 4],
+        width: 8,
+    },
+];
+
+/// FCFG_BANK out of reset, with the row widths of `BANKS` in bits including ECC.
+fn fcfg_bank_reset() -> u32 {
+    let bits = |tech| {
+        let bank = BANKS.iter().find(|bank| bank.tech == tech).unwrap();
+        bank.width as u32 / 8 * 72
+    };
+    bits(FLEE) << 20 | bits(FLEP) << 4
+}
+
+impl BankLayout {
+    fn size(&self) -> u32 {
+        self.sectors.iter().map(|&kb| kb as u32 * 1024).sum()
+    }
+
+    fn contains(&self, address: u32) -> bool {
+        address >= self.start && address - self.start < self.size()
+    }
+
+    /// Return the index, start and size of the sector containing `address`.
+    fn sector(&self, address: u32) -> Option<(usize, u32, u32)> {
+        let mut start = self.start;
+        for (index, &kb) in self.sectors.iter().enumerate() {
+            let size = kb as u32 * 1024;
+            if address >= start && address - start < size {
+                return Some((index, start, size));
+            }
+            start += size;
+        }
+        None
+    }
+
+    fn otp_start(&self) -> u32 {
+        device::OTP_ADDRESS + self.bank as u32 * device::OTP_BANK_SIZE
+    }
+}
+
+/// Which array of a bank an address refers to.
+#[derive(Clone, Copy, PartialEq)]
+enum Region {
+    Data,
+    Otp,
+}
+
+struct Bank {
+    layout: &'static BankLayout,
+    data: Vec<u8>,
+    /// One ECC byte for every 64-bit word of `data`
+    ecc: Vec<u8>,
+    otp: Vec<u8>,
+    otp_ecc: Vec<u8>,
+    /// One bit per sector, set if the sector may be erased or programmed
+    sector_enables: u64,
+}
+
+impl Bank {
+    fn new(layout: &'static BankLayout) -> Self {
+        let size = layout.size() as usize;
+        let otp_size = device::OTP_BANK_SIZE as usize;
+        Bank {
+            layout,
+            data: vec![0xff; size],
+            ecc: vec![0xff; size / 8],
+            otp: vec![0xff; otp_size],
+            otp_ecc: vec![0xff; otp_size / 8],
+            sector_enables: 0,
+        }
+    }
+
+    /// Return the region and offset of `address` in this bank, if any.
+    fn locate(&self, address: u32) -> Option<(Region, usize)> {
+        if self.layout.contains(address) {
+            Some((Region::Data, (address - self.layout.start) as usize))
+        } else if address >= self.layout.otp_start()
+            && address - self.layout.otp_start() < device::OTP_BANK_SIZE
+        {
+            Some((Region::Otp, (address - self.layout.otp_start()) as usize))
+        } else {
+            None
+        }
+    }
+
+    fn arrays(&mut self, region: Region) -> (&mut Vec<u8>, &mut Vec<u8>) {
+        match region {
+            Region::Data => (&mut self.data, &mut self.ecc),
+            Region::Otp => (&mut self.otp, &mut self.otp_ecc),
+        }
+    }
+
+    fn erase_sector(&mut self, index: usize) {
+        let mut start = 0usize;
+        for &kb in &self.layout.sectors[..index] {
+            start += kb as usize * 1024;
+        }
+        let size = self.layout.sectors[index] as usize * 1024;
+        self.data[start..start + size].fill(0xff);
+        self.ecc[start / 8..(start + size) / 8].fill(0xff);
+    }
+}
+
+struct Device {
+    banks: Vec<Bank>,
+    /// Index into `banks` of the active bank
+    active_bank: usize,
+    fmstat: u32,
+    /// The number of FMSTAT reads until the current operation finishes
+    busy_reads: u32,
+    /// Error bits that will be reported once the current operation finishes
+    pending_status: u32,
+    /// The value of `busy_reads` when the current erase was suspended
+    suspended_reads: u32,
+    /// The HCLK passed to `Fapi_initializeFlashBanks()`
+    hclk: u32,
+    /// The PMU cycle counter
+    cycles: u32,
+    /// PMCR and PMCNTENSET, which only matter for checking that they are restored
+    pmcr: u32,
+    pmcntenset: u32,
+    /// `true` if the next operation should never finish
+    hang: bool,
+    /// Bits that are only just programmed, which read as erased under margin 1
+    weak: HashMap<u32, u8>,
+    /// Every other register, which simply holds the last value written to it
+    registers: HashMap<usize, u32>,
+}
+
+impl Device {
+    fn new() -> Self {
+        Device {
+            banks: BANKS.iter().map(Bank::new).collect(),
+            active_bank: 0,
+            fmstat: 0,
+            busy_reads: 0,
+            pending_status: 0,
+            suspended_reads: 0,
+            hclk: 0,
+            cycles: 0,
+            pmcr: 0,
+            pmcntenset: 0,
+            hang: false,
+            weak: HashMap::new(),
+            registers: HashMap::from([
+                (device::FSM_EXECUTE as usize, FSM_EXECUTE_RESET),
+                (device::FCFG_BANK as usize, fcfg_bank_reset()),
+            ]),
+        }
+    }
+
+    fn bank_index(&self, bank: u8) -> Option<usize> {
+        self.banks.iter().position(|b| b.layout.bank == bank)
+    }
+
+    /// Return the bank, region and offset of `address`, if it is flash or OTP.
+    fn locate(&self, address: u32) -> Option<(usize, Region, usize)> {
+        self.banks
+            .iter()
+            .enumerate()
+            .find_map(|(index, bank)| bank.locate(address).map(|(r, o)| (index, r, o)))
+    }
+
+    fn busy(&self) -> bool {
+        self.busy_reads > 0
+    }
+
+    /// Start an FSM operation that will report `status` once it finishes.
+    fn start(&mut self, operation: u32, busy_reads: u32, status: u32) {
+        self.fmstat |= operation | FMSTAT_BUSY;
+        self.busy_reads = if self.hang { u32::MAX } else { busy_reads };
+        self.pending_status = status;
+    }
+
+    fn read_fmstat(&mut self) -> u32 {
+        if self.busy_reads > 0 {
+            self.busy_reads -= 1;
+            if self.busy_reads == 0 {
+                self.fmstat &= !(FMSTAT_BUSY | FMSTAT_PGM | FMSTAT_ERS);
+                self.fmstat |= self.pending_status;
+                self.pending_status = 0;
+            }
+        }
+        self.fmstat
+    }
+
+    /// Suspend the erase in progress. Its result is held back until it is resumed.
+    fn suspend(&mut self) {
+        if self.busy() && self.fmstat & FMSTAT_ERS != 0 {
+            self.suspended_reads = self.busy_reads;
+            self.busy_reads = 0;
+            self.fmstat = (self.fmstat & !FMSTAT_BUSY) | FMSTAT_ESUSP;
+        }
+    }
+
+    fn resume(&mut self) {
+        if self.fmstat & FMSTAT_ESUSP != 0 {
+            self.fmstat = (self.fmstat & !FMSTAT_ESUSP) | FMSTAT_BUSY;
+            self.busy_reads = self.suspended_reads;
+        }
+    }
+
+    /// The number of data bytes in one row of banks of technology `tech`, which is
+    /// whatever FCFG_BANK says rather than the width the bank was built with.
+    fn row_width(&self, tech: u8) -> usize {
+        let fcfg_bank = self.registers.get(&(device::FCFG_BANK as usize)).copied();
+        let fcfg_bank = fcfg_bank.unwrap_or(0);
+        let bits = if tech == FLEE {
+            fcfg_bank >> 20
+        } else {
+            fcfg_bank >> 4
+        } & 0xfff;
+        bits as usize / 72 * 8
+    }
+
+    fn otp_unlocked(&self, bank: u8) -> bool {
+        let fbac = self.registers.get(&(device::FBAC as usize)).copied();
+        fbac.unwrap_or(0) & (1 << (16 + bank as u32)) != 0
+    }
+
+    fn erase_sector(&mut self, address: u32) -> u32 {
+        let bank = &mut self.banks[self.active_bank];
+        let Some((index, _, _)) = bank.layout.sector(address) else {
+            return FMSTAT_ILA | FMSTAT_CSTAT;
+        };
+        if bank.sector_enables & (1 << index) == 0 {
+            return FMSTAT_SLOCK | FMSTAT_CSTAT;
+        }
+        bank.erase_sector(index);
+        0
+    }
+
+    fn erase_bank(&mut self, address: u32) -> u32 {
+        let bank = &mut self.banks[self.active_bank];
+        if !bank.layout.contains(address) {
+            return FMSTAT_ILA | FMSTAT_CSTAT;
+        }
+        // Only the enabled sectors are erased
+        for index in 0..bank.layout.sectors.len() {
+            if bank.sector_enables & (1 << index) != 0 {
+                bank.erase_sector(index);
+            }
+        }
+        0
+    }
+
+    /// Program `data` and `ecc` into the active bank, returning the FMSTAT error bits.
+    fn program(
+        &mut self,
+        address: u32,
+        data: &[u8],
+        ecc: &[u8],
+        mode: Fapi_FlashProgrammingCommandsType,
+    ) -> u32 {
+        let Some((index, region, offset)) = self.locate(address) else {
+            return FMSTAT_ILA | FMSTAT_CSTAT;
+        };
+        if index != self.active_bank {
+            return FMSTAT_ILA | FMSTAT_CSTAT;
+        }
+        let bank_number = self.banks[index].layout.bank;
+        let locked = match region {
+            Region::Data => {
+                let (sector, _, _) = self.banks[index].layout.sector(address).unwrap();
+                self.banks[index].sector_enables & (1 << sector) == 0
+            }
+            Region::Otp => !self.otp_unlocked(bank_number),
+        };
+        if locked {
+            return FMSTAT_SLOCK | FMSTAT_CSTAT;
+        }
+
+        let (program_data, program_ecc) = match mode {
+            Fapi_FlashProgrammingCommandsType::Fapi_AutoEccGeneration => (true, false),
+            Fapi_FlashProgrammingCommandsType::Fapi_DataOnly => (true, false),
+            Fapi_FlashProgrammingCommandsType::Fapi_EccOnly => (false, true),
+            Fapi_FlashProgrammingCommandsType::Fapi_DataAndEcc => (true, true),
+        };
+        let auto_ecc = matches!(
+            mode,
+            Fapi_FlashProgrammingCommandsType::Fapi_AutoEccGeneration
+        );
+
+        let (flash, flash_ecc) = self.banks[index].arrays(region);
+        let words = offset / 8..(offset + data.len()).div_ceil(8);
+        let mut new_data = flash[words.start * 8..words.end * 8].to_vec();
+        let mut new_ecc = flash_ecc[words.clone()].to_vec();
+        if program_data {
+            for (c, &d) in new_data[offset % 8..].iter_mut().zip(data) {
+                *c = d;
+            }
+        }
+        if auto_ecc {
+            for (word, e) in words.clone().zip(&mut new_ecc) {
+                let word_address = address - offset as u32 + (word * 8) as u32;
+                let bytes = &new_data[(word - words.start) * 8..][..8];
+                *e = ecc_for(word_address, bytes.try_into().unwrap());
+            }
+        }
+        if program_ecc {
+            for (e, &new) in new_ecc.iter_mut().zip(ecc) {
+                *e = new;
+            }
+        }
+
+        // Programming can only clear bits, in the data and in the ECC alike.
+        // Attempting to set a bit fails the whole operation without changing
+        // anything.
+        let current_data = &flash[words.start * 8..words.end * 8];
+        let current_ecc = &flash_ecc[words.clone()];
+        let sets_bit =
+            |current: &[u8], new: &[u8]| current.iter().zip(new).any(|(&c, &n)| !c & n != 0);
+        if sets_bit(current_data, &new_data) || sets_bit(current_ecc, &new_ecc) {
+            return FMSTAT_INVDAT | FMSTAT_CSTAT;
+        }
+        flash[words.start * 8..words.end * 8].copy_from_slice(&new_data);
+        flash_ecc[words].copy_from_slice(&new_ecc);
+        0
+    }
+}
+
+std::thread_local! {
+    static DEVICE: RefCell<Device> = RefCell::new(Device::new());
+}
+
+fn with_device<R>(f: impl FnOnce(&mut Device) -> R) -> R {
+    DEVICE.with(|device| f(&mut device.borrow_mut()))
+}
+
+/// The simulated ECC for a 64-bit word: every bit of the address and data folded
+/// together. On the TMS570LS31x erased data has erased ECC, but the TMS570LC43x
+/// covers the address as well, so its erased ECC is never valid.
+pub fn ecc_for(address: u32, data: &[u8; 8]) -> u8 {
+    if cfg!(feature = "ls3137") && data.iter().all(|&b| b == 0xff) {
+        return 0xff;
+    }
+    let word = u64::from_ne_bytes(*data);
+    let folded = word ^ (word >> 32) ^ (address as u64 & !7);
+    let folded = folded ^ (folded >> 16);
+    (folded ^ (folded >> 8)) as u8
+}
+
+/// Power-cycle the simulated device, erasing every bank and the OTP.
+pub fn reset() {
+    with_device(|device| *device = Device::new());
+}
+
+/// Make every following FSM operation stay busy for as long as anyone cares to
+/// wait, like a wedged FSM.
+pub fn hang() {
+    with_device(|device| device.hang = true);
+}
+
+/// Mark the programmed bits of `mask` at `address` as only just programmed, so
+/// that they read as erased under margin 1 until the device is reset.
+pub fn weaken(address: u32, mask: u8) {
+    with_device(|device| *device.weak.entry(address).or_default() |= mask);
+}
+
+/// The HCLK in MHz that the flash API was last initialized with.
+pub fn hclk() -> u32 {
+    with_device(|device| device.hclk)
+}
+
+pub unsafe fn Fapi_initializeFlashBanks(u32HclkFrequency: u32) -> u32 {
+    if u32HclkFrequency == 0 {
+        return ERROR_INVALID_HCLK_VALUE;
+    }
+    with_device(|device| {
+        // Like the real API, refuse an HCLK that the wait states are too few for
+        let register = |r: *mut u32| device.registers.get(&(r as usize)).copied().unwrap_or(0);
+        let rwait = (register(device::FRDCNTL) >> 8) & 15;
+        let ewait = (register(device::EWAIT) >> 16) & 15;
+        let limits = &device::WAIT_STATE_LIMITS;
+        if u32HclkFrequency > (rwait + 1) * limits.rwait_step_mhz
+            || u32HclkFrequency > (ewait + 1) * limits.ewait_step_mhz
+        {
+            return ERROR_INVALID_HCLK_VALUE;
+        }
+        device.hclk = u32HclkFrequency;
+        SUCCESS
+    })
+}
+
+pub unsafe fn Fapi_setActiveFlashBank(oNewFlashBank: Fapi_FlashBankType) -> u32 {
+    let bank = oNewFlashBank as u8;
+    with_device(|device| match device.bank_index(bank) {
+        Some(index) => {
+            device.active_bank = index;
+            let fmac = device::FMAC as usize;
+            let previous = device.registers.get(&fmac).copied().unwrap_or(0);
+            device.registers.insert(fmac, (previous & !7) | bank as u32);
+            SUCCESS
+        }
+        None => ERROR_INVALID_BANK,
+    })
+}
+
+pub unsafe fn Fapi_enableMainBankSectors(u16SectorsEnables: u16) -> u32 {
+    with_device(|device| {
+        let bank = &mut device.banks[device.active_bank];
+        if bank.layout.tech == FLEE {
+            return ERROR_INVALID_BANK;
+        }
+        bank.sector_enables = u16SectorsEnables as u64;
+        SUCCESS
+    })
+}
+
+pub unsafe fn Fapi_enableEepromBankSectors(
+    u32SectorsEnables_31_0: u32,
+    u32SectorsEnables_63_32: u32,
+) -> u32 {
+    with_device(|device| {
+        let bank = &mut device.banks[device.active_bank];
+        if bank.layout.tech != FLEE {
+            return ERROR_INVALID_BANK;
+        }
+        bank.sector_enables =
+            ((u32SectorsEnables_63_32 as u64) << 32) | u32SectorsEnables_31_0 as u64;
+        SUCCESS
+    })
+}
+
+pub unsafe fn Fapi_issueAsyncCommand(oCommand: Fapi_FlashStateCommandsType) -> u32 {
+    with_device(|device| match oCommand {
+        Fapi_FlashStateCommandsType::Fapi_ClearStatus
+        | Fapi_FlashStateCommandsType::Fapi_ClearMore => {
+            device.fmstat &= FMSTAT_BUSY | FMSTAT_PGM | FMSTAT_ERS | FMSTAT_ESUSP;
+            SUCCESS
+        }
+        Fapi_FlashStateCommandsType::Fapi_EraseResume => {
+            device.resume();
+            SUCCESS
+        }
+        Fapi_FlashStateCommandsType::Fapi_ProgramResume => SUCCESS,
+        _ => ERROR_INVALID_COMMAND,
+    })
+}
+
+pub unsafe fn Fapi_issueAsyncCommandWithAddress(
+    oCommand: Fapi_FlashStateCommandsType,
+    pu32StartAddress: *mut u32,
+) -> u32 {
+    let address = pu32StartAddress as usize as u32;
+    with_device(|device| {
+        if device.busy() {
+            return FSM_BUSY;
+        }
+        let status = match oCommand {
+            Fapi_FlashStateCommandsType::Fapi_EraseSector => device.erase_sector(address),
+            Fapi_FlashStateCommandsType::Fapi_EraseBank => device.erase_bank(address),
+            _ => return ERROR_INVALID_COMMAND,
+        };
+        device.start(FMSTAT_ERS, ERASE_BUSY_READS, status);
+        SUCCESS
+    })
+}
+
+pub unsafe fn Fapi_issueProgrammingCommand(
+    pu32StartAddress: *mut u32,
+    pu8DataBuffer: *const u8,
+    u8DataBufferSizeInBytes: u8,
+    pu8EccBuffer: *const u8,
+    u8EccBufferSizeInBytes: u8,
+    oMode: Fapi_FlashProgrammingCommandsType,
+) -> u32 {
+    let address = pu32StartAddress as usize as u32;
+    let data =
+        unsafe { core::slice::from_raw_parts(pu8DataBuffer, u8DataBufferSizeInBytes.into()) };
+    let ecc = if pu8EccBuffer.is_null() {
+        &[][..]
+    } else {
+        unsafe { core::slice::from_raw_parts(pu8EccBuffer, u8EccBufferSizeInBytes.into()) }
+    };
+    with_device(|device| {
+        if device.busy() {
+            return FSM_BUSY;
+        }
+        let Some((index, _, _)) = device.locate(address) else {
+            return ERROR_INVALID_ADDRESS;
+        };
+        // The data may not cross a row of the bank, and the ECC may not cover more
+        // than one row.
+        let width = device.row_width(device.banks[index].layout.tech);
+        if width == 0 || data.is_empty() || address as usize % width + data.len() > width {
+            return ERROR_INCORRECT_DATA_BUFFER_LENGTH;
+        }
+        let uses_ecc = matches!(
+            oMode,
+            Fapi_FlashProgrammingCommandsType::Fapi_EccOnly
+                | Fapi_FlashProgrammingCommandsType::Fapi_DataAndEcc
+        );
+        if uses_ecc {
+            if ecc.len() > width / 8 {
+                return ERROR_INCORRECT_ECC_BUFFER_LENGTH;
+            }
+            if !address.is_multiple_of(8)
+                || !data.len().is_multiple_of(8)
+                || data.len() / 8 != ecc.len()
+            {
+                return ERROR_DATA_ECC_BUFFER_LENGTH_MISMATCH;
+            }
+        }
+        let status = device.program(address, data, ecc, oMode);
+        device.start(FMSTAT_PGM, PROGRAM_BUSY_READS, status);
+        SUCCESS
+    })
+}
+
+pub unsafe fn Fapi_getBankSectors(
+    oBank: Fapi_FlashBankType,
+    poFlashBankSectors: *mut Fapi_FlashBankSectorsType,
+) -> u32 {
+    let bank = oBank as u8;
+    let Some(layout) = BANKS.iter().find(|b| b.bank == bank) else {
+        return ERROR_INVALID_BANK;
+    };
+    let sectors = unsafe { &mut *poFlashBankSectors };
+    sectors.oFlashBankTech = layout.tech;
+    sectors.u32NumberOfSectors = layout.sectors.len() as u32;
+    sectors.u32BankStartAddress = layout.start;
+    // Only the first sector size is reported for FLEE banks
+    let reported = if layout.tech == FLEE {
+        &layout.sectors[..1]
+    } else {
+        layout.sectors
+    };
+    for (size, &kb) in sectors.au16SectorSizes.iter_mut().zip(reported) {
+        *size = kb;
+    }
+    SUCCESS
+}
+
+pub unsafe fn Fapi_getLibraryInfo() -> Fapi_LibraryInfoType {
+    Fapi_LibraryInfoType {
+        u8ApiMajorVersion: 2,
+        u8ApiMinorVersion: 1,
+        u8ApiRevision: 1,
+        oApiProductionStatus: 4,
+        u32ApiBuildNumber: 0,
+        u8ApiTechnologyType: 0,
+        u8ApiTechnologyRevision: 0,
+        u8ApiEndianness: cfg!(target_endian = "little") as u8,
+        u32ApiCompilerVersion: 0,
+    }
+}
+
+pub unsafe fn Fapi_getDeviceInfo() -> Fapi_DeviceInfoType {
+    Fapi_DeviceInfoType {
+        u16Reserved: 0,
+        // Only the main banks are counted, so nothing can rely on the count
+        // reaching the EEPROM emulation bank
+        u16NumberOfBanks: BANKS.iter().filter(|b| b.tech == FLEP).count() as u16,
+        u16DevicePackage: 0,
+        u16DeviceMemorySize: 0,
+        u32AsicId: 0,
+        u32LotNumber: 0,
+        u16FlowCheck: 0,
+        u16WaferNumber: 0,
+        u16WaferXCoordinate: 0,
+        u16WaferYCoordinate: 0,
+    }
+}
+
+/// Compare `length` bytes at `address` against `expected`, filling in `status` with
+/// the first mismatched 32-bit word. Like the API, an address outside of the banks
+/// is refused without touching `status`.
+unsafe fn compare(
+    address: u32,
+    length: usize,
+    expected: impl Fn(usize) -> u8,
+    status: *mut Fapi_FlashStatusWordType,
+) -> u32 {
+    if with_device(|device| device.locate(address).is_none()) {
+        return ERROR_INVALID_ADDRESS;
+    }
+    let mut actual = vec![0u8; length];
+    unsafe { read_flash(address, &mut actual) };
+    let Some(mismatch) = (0..length).find(|&i| actual[i] != expected(i)) else {
+        return SUCCESS;
+    };
+    let word = mismatch & !3;
+    let end = (word + 4).min(length);
+    let mut actual_word = [0u8; 4];
+    let mut expected_word = [0u8; 4];
+    for i in word..end {
+        actual_word[i - word] = actual[i];
+        expected_word[i - word] = expected(i);
+    }
+    let status = unsafe { &mut *status };
+    status.au32StatusWord = [
+        address + word as u32,
+        u32::from_ne_bytes(actual_word),
+        u32::from_ne_bytes(expected_word),
+        0,
+    ];
+    ERROR_FAIL
+}
+
+pub unsafe fn Fapi_doBlankCheck(
+    pu32StartAddress: *const u32,
+    u32Length: u32,
+    poFlashStatusWord: *mut Fapi_FlashStatusWordType,
+) -> u32 {
+    let address = pu32StartAddress as usize as u32;
+    unsafe { compare(address, u32Length as usize * 4, |_| 0xff, poFlashStatusWord) }
+}
+
+pub unsafe fn Fapi_doBlankCheckByByte(
+    pu32StartAddress: *const u32,
+    u32Length: u32,
+    poFlashStatusWord: *mut Fapi_FlashStatusWordType,
+) -> u32 {
+    let address = pu32StartAddress as usize as u32;
+    unsafe { compare(address, u32Length as usize, |_| 0xff, poFlashStatusWord) }
+}
+
+pub unsafe fn Fapi_doVerify(
+    pu32StartAddress: *const u32,
+    u32Length: u32,
+    pu32CheckValueBuffer: *const u32,
+    poFlashStatusWord: *mut Fapi_FlashStatusWordType,
+) -> u32 {
+    let address = pu32StartAddress as usize as u32;
+    let length = u32Length as usize * 4;
+    let expected =
+        unsafe { core::slice::from_raw_parts(pu32CheckValueBuffer as *const u8, length) };
+    unsafe { compare(address, length, |i| expected[i], poFlashStatusWord) }
+}
+
+pub unsafe fn Fapi_doVerifyByByte(
+    pu8StartAddress: *const u8,
+    u32Length: u32,
+    pu8CheckValueBuffer: *const u8,
+    poFlashStatusWord: *mut Fapi_FlashStatusWordType,
+) -> u32 {
+    let address = pu8StartAddress as usize as u32;
+    let length = u32Length as usize;
+    let expected = unsafe { core::slice::from_raw_parts(pu8CheckValueBuffer, length) };
+    unsafe { compare(address, length, |i| expected[i], poFlashStatusWord) }
+}
+
+/// Read flash in `mode`, where margin 1 shows the bits marked by `weaken()` as erased.
+unsafe fn margin_read(address: u32, buffer: &mut [u8], mode: Fapi_FlashReadMarginModeType) -> u32 {
+    unsafe { read_flash(address, buffer) };
+    if matches!(mode, Fapi_FlashReadMarginModeType::Fapi_RM1) {
+        with_device(|device| {
+            for (offset, byte) in buffer.iter_mut().enumerate() {
+                *byte |= device
+                    .weak
+                    .get(&(address + offset as u32))
+                    .copied()
+                    .unwrap_or(0);
+            }
+        });
+    }
+    SUCCESS
+}
+
+pub unsafe fn Fapi_doMarginRead(
+    pu32StartAddress: *const u32,
+    pu32ReadBuffer: *mut u32,
+    u32Length: u32,
+    oReadMode: Fapi_FlashReadMarginModeType,
+) -> u32 {
+    let address = pu32StartAddress as usize as u32;
+    let length = u32Length as usize * 4;
+    let buffer = unsafe { core::slice::from_raw_parts_mut(pu32ReadBuffer as *mut u8, length) };
+    unsafe { margin_read(address, buffer, oReadMode) }
+}
+
+pub unsafe fn Fapi_doMarginReadByByte(
+    pu8StartAddress: *const u8,
+    pu8ReadBuffer: *mut u8,
+    u32Length: u32,
+    oReadMode: Fapi_FlashReadMarginModeType,
+) -> u32 {
+    let address = pu8StartAddress as usize as u32;
+    let buffer = unsafe { core::slice::from_raw_parts_mut(pu8ReadBuffer, u32Length as usize) };
+    unsafe { margin_read(address, buffer, oReadMode) }
+}
+
+pub unsafe fn Fapi_calculateFletcherChecksum(u32Address: u32, u32Length: u32) -> u32 {
+    let mut buffer = vec![0u8; u32Length as usize * 2];
+    unsafe { read_flash(u32Address, &mut buffer) };
+    let (mut sum1, mut sum2) = (0xffff_u32, 0xffff_u32);
+    for word in buffer.chunks(2) {
+        sum1 += u16::from_ne_bytes([word[0], word[1]]) as u32;
+        sum1 = (sum1 & 0xffff) + (sum1 >> 16);
+        sum2 += sum1;
+        sum2 = (sum2 & 0xffff) + (sum2 >> 16);
+    }
+    (sum2 << 16) | sum1
+}
+
+/// A 32-bit MISR over the words of flash read in `mode`. The feedback taps are not
+/// the ones the flash wrapper uses, so the signatures only mean something within
+/// the sim.
+unsafe fn psa(address: u32, length: u32, seed: u32, mode: Fapi_FlashReadMarginModeType) -> u32 {
+    let mut buffer = vec![0u8; length as usize * 4];
+    unsafe { margin_read(address, &mut buffer, mode) };
+    buffer.chunks(4).fold(seed, |psa, word| {
+        let feedback = if psa & 0x8000_0000 != 0 {
+            0x0440_0007
+        } else {
+            0
+        };
+        (psa << 1) ^ feedback ^ u32::from_ne_bytes(word.try_into().unwrap())
+    })
+}
+
+pub unsafe fn Fapi_calculatePsa(
+    pu32StartAddress: *const u32,
+    u32Length: u32,
+    u32PsaSeed: u32,
+    oReadMode: Fapi_FlashReadMarginModeType,
+) -> u32 {
+    let address = pu32StartAddress as usize as u32;
+    unsafe { psa(address, u32Length, u32PsaSeed, oReadMode) }
+}
+
+/// Checks the PSA from a seed of 0 under both read margins.
+pub unsafe fn Fapi_doPsaVerify(
+    pu32StartAddress: *const u32,
+    u32Length: u32,
+    u32PsaValue: u32,
+    poFlashStatusWord: *mut Fapi_FlashStatusWordType,
+) -> u32 {
+    let address = pu32StartAddress as usize as u32;
+    for mode in [
+        Fapi_FlashReadMarginModeType::Fapi_RM0,
+        Fapi_FlashReadMarginModeType::Fapi_RM1,
+    ] {
+        let actual = unsafe { psa(address, u32Length, 0, mode) };
+        if actual != u32PsaValue {
+            let status = unsafe { &mut *poFlashStatusWord };
+            status.au32StatusWord = [address, actual, u32PsaValue, mode as u32];
+            return ERROR_FAIL;
+        }
+    }
+    SUCCESS
+}
+
+pub unsafe fn Fapi_flushPipeline() {}
+
+pub unsafe fn read_register(register: *mut u32) -> u32 {
+    with_device(|device| {
+        if register == FMSTAT_ADDRESS {
+            device.read_fmstat()
+        } else {
+            device
+                .registers
+                .get(&(register as usize))
+                .copied()
+                .unwrap_or(0)
+        }
+    })
+}
+
+pub unsafe fn write_register(register: *mut u32, value: u32) {
+    with_device(|device| {
+        if register == device::FSM_EXECUTE {
+            if (value >> 16) & 15 == SUSPEND_NOW {
+                device.suspend();
+            }
+            // Starting the command again picks a suspended erase back up
+            if value & 0x1f == FSMEXECUTE_START {
+                device.resume();
+            }
+        }
+        // FMAC selects the active bank, just like `Fapi_setActiveFlashBank()`
+        if register == device::FMAC
+            && let Some(index) = device.bank_index((value & 7) as u8)
+        {
+            device.active_bank = index;
+        }
+        device.registers.insert(register as usize, value);
+    })
+}
+
+pub unsafe fn read_cycle_counter_config() -> (u32, u32) {
+    with_device(|device| (device.pmcr, device.pmcntenset))
+}
+
+pub unsafe fn write_cycle_counter_config(pmcr: u32, pmcntenset: u32) {
+    with_device(|device| {
+        device.pmcr = pmcr & !0b110;
+        device.pmcntenset = (device.pmcntenset & !(1 << 31)) | (pmcntenset & (1 << 31));
+    })
+}
+
+/// Sets PMCR.E, clears PMCR.D and sets PMCNTENSET.C, like the hardware version.
+pub unsafe fn enable_cycle_counter() {
+    with_device(|device| {
+        device.pmcr = (device.pmcr | 1) & !0b1110;
+        device.pmcntenset |= 1 << 31;
+    })
+}
+
+/// Each read of the cycle counter advances it by `CYCLES_PER_READ`, so that
+/// deadlines pass quickly regardless of how fast the host is.
+pub unsafe fn read_cycle_counter() -> u32 {
+    with_device(|device| {
+        device.cycles = device.cycles.wrapping_add(CYCLES_PER_READ);
+        device.cycles
+    })
+}
+
+/// Read flash, OTP, or the ECC mirror of main flash. Panics on any other address,
+/// since that would fault on hardware.
+pub unsafe fn read_flash(address: u32, buffer: &mut [u8]) {
+    with_device(|device| {
+        for (offset, byte) in buffer.iter_mut().enumerate() {
+            let address = address + offset as u32;
+            if let Some((index, region, offset)) = device.locate(address) {
+                let (flash, _) = device.banks[index].arrays(region);
+                *byte = flash[offset];
+                continue;
+            }
+            let ecc_offset = address.wrapping_sub(device::ECC_ADDRESS);
+            if ecc_offset < device::ECC_SIZE
+                && let Some((index, Region::Data, offset)) = device.locate(ecc_offset * 8)
+            {
+                *byte = device.banks[index].ecc[offset / 8];
+                continue;
+            }
+            panic!("read of unmapped address 0x{:08x}", address);
+        }
+    })
+}
+
+pub unsafe fn invalidate_caches() {}
+
+pub unsafe fn disable_ecc() {}
+
+pub unsafe fn enable_ecc() {}
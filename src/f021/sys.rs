@@ -43,6 +43,17 @@ pub enum Fapi_FlashProgrammingCommandsType {
     Fapi_DataAndEcc,
 }
 
+/// This contains the read modes used by Fapi_doMarginRead(), which are also reported
+/// in the last word of `Fapi_FlashStatusWordType`.
+#[repr(u8)]
+#[allow(non_camel_case_types, dead_code)]
+#[derive(Clone, Copy)]
+pub enum Fapi_FlashReadMarginModeType {
+    Fapi_NormalRead = 0x0,
+    Fapi_RM0 = 0x1,
+    Fapi_RM1 = 0x2,
+}
+
 //// This contains all the possible Flash State Machine commands.
 #[repr(u8)]
 #[allow(non_camel_case_types, dead_code)]
@@ -186,6 +197,7 @@ extern "C" fn Fapi_serviceWatchdogTimer() -> u32 {
     0
 }
 
+#[cfg(not(feature = "sim"))]
 #[allow(dead_code)]
 unsafe extern "C" {
     pub fn Fapi_initializeFlashBanks(u32HclkFrequency: u32) -> u32 /* Fapi_StatusType */;
@@ -199,9 +211,7 @@ unsafe extern "C" {
         u32SectorsEnables_63_32: u32,
     ) -> u32 /* Fapi_StatusType */;
 
-    pub fn Fapi_issueAsyncCommand(
-        oCommand: Fapi_FlashStateCommandsType,
-    ) -> u32 /* Fapi_StatusType */;
+    pub fn Fapi_issueAsyncCommand(oCommand: Fapi_FlashStateCommandsType) -> u32 /* Fapi_StatusType */;
 
     pub fn Fapi_issueAsyncCommandWithAddress(
         oCommand: Fapi_FlashStateCommandsType,
@@ -238,5 +248,181 @@ unsafe extern "C" {
         poFlashStatusWord: *mut Fapi_FlashStatusWordType,
     ) -> u32 /* Fapi_StatusType */;
 
+    pub fn Fapi_doVerify(
+        pu32StartAddress: *const u32,
+        u32Length: u32,
+        pu32CheckValueBuffer: *const u32,
+        poFlashStatusWord: *mut Fapi_FlashStatusWordType,
+    ) -> u32 /* Fapi_StatusType */;
+
+    pub fn Fapi_doVerifyByByte(
+        pu8StartAddress: *const u8,
+        u32Length: u32,
+        pu8CheckValueBuffer: *const u8,
+        poFlashStatusWord: *mut Fapi_FlashStatusWordType,
+    ) -> u32 /* Fapi_StatusType */;
+
+    pub fn Fapi_doMarginRead(
+        pu32StartAddress: *const u32,
+        pu32ReadBuffer: *mut u32,
+        u32Length: u32,
+        oReadMode: Fapi_FlashReadMarginModeType,
+    ) -> u32 /* Fapi_StatusType */;
+
+    pub fn Fapi_doMarginReadByByte(
+        pu8StartAddress: *const u8,
+        pu8ReadBuffer: *mut u8,
+        u32Length: u32,
+        oReadMode: Fapi_FlashReadMarginModeType,
+    ) -> u32 /* Fapi_StatusType */;
+
+    pub fn Fapi_calculateFletcherChecksum(u32Address: u32, u32Length: u32) -> u32;
+
+    pub fn Fapi_calculatePsa(
+        pu32StartAddress: *const u32,
+        u32Length: u32,
+        u32PsaSeed: u32,
+        oReadMode: Fapi_FlashReadMarginModeType,
+    ) -> u32;
+
+    pub fn Fapi_doPsaVerify(
+        pu32StartAddress: *const u32,
+        u32Length: u32,
+        u32PsaValue: u32,
+        poFlashStatusWord: *mut Fapi_FlashStatusWordType,
+    ) -> u32 /* Fapi_StatusType */;
+
     pub fn Fapi_flushPipeline();
 }
+
+// The simulated API has the same signatures as the real one, so it can stand in
+// for everything below.
+#[cfg(feature = "sim")]
+pub use super::sim::*;
+
+/// Read a memory-mapped register.
+#[cfg(not(feature = "sim"))]
+pub unsafe fn read_register(register: *mut u32) -> u32 {
+    unsafe { register.read_volatile() }
+}
+
+/// Write a memory-mapped register.
+#[cfg(not(feature = "sim"))]
+pub unsafe fn write_register(register: *mut u32, value: u32) {
+    unsafe { register.write_volatile(value) }
+}
+
+/// Read `buffer.len()` bytes of flash starting at `address`.
+#[cfg(not(feature = "sim"))]
+pub unsafe fn read_flash(address: u32, buffer: &mut [u8]) {
+    for (offset, byte) in buffer.iter_mut().enumerate() {
+        *byte = unsafe { ((address as usize + offset) as *const u8).read_volatile() };
+    }
+}
+
+/// Invalidate D$ and I$
+#[cfg(not(feature = "sim"))]
+pub unsafe fn invalidate_caches() {
+    unsafe {
+        core::arch::asm!("
+            mov   r0,#0
+            dsb
+            mcr   p15, #0, r0, c15, c5, #0 // dcache
+            mcr   p15, #0, r0, c7, c5, #0 // icache
+            dsb
+        ", out("r0") _);
+    }
+}
+
+#[cfg(not(feature = "sim"))]
+pub unsafe fn disable_ecc() {
+    unsafe {
+        core::arch::asm!(
+            "
+                mrc   p15, #0x00, r0,         c1, c0,  #0x01
+                bic   r0,  r0,    #0x02000000
+                mcr   p15, #0x00, r0,         c1, c0,  #0x01
+                isb
+            ",
+            out("r0") _
+        );
+    }
+}
+
+#[cfg(not(feature = "sim"))]
+pub unsafe fn enable_ecc() {
+    unsafe {
+        core::arch::asm!(
+            "
+                mrc   p15, #0x00, r0,         c1, c0,  #0x01
+                orr   r0,  r0,    #0x02000000
+                dmb
+                mcr   p15, #0x00, r0,         c1, c0,  #0x01
+                isb
+            ",
+            out("r0") _
+        )
+    }
+}
+
+/// PMCR.E, which enables the PMU counters
+#[cfg(not(feature = "sim"))]
+const PMCR_E: u32 = 1 << 0;
+/// PMCR.P and PMCR.C, which reset the counters when written as 1
+#[cfg(not(feature = "sim"))]
+const PMCR_RESETS: u32 = 0b11 << 1;
+/// PMCR.D, which makes the cycle counter count every 64th cycle
+#[cfg(not(feature = "sim"))]
+const PMCR_D: u32 = 1 << 3;
+/// PMCNTENSET.C and PMCNTENCLR.C, which enable and disable the cycle counter
+#[cfg(not(feature = "sim"))]
+const PMCNTENSET_C: u32 = 1 << 31;
+
+/// Read PMCR and PMCNTENSET, which `enable_cycle_counter()` changes.
+#[cfg(not(feature = "sim"))]
+pub unsafe fn read_cycle_counter_config() -> (u32, u32) {
+    let pmcr: u32;
+    let pmcntenset: u32;
+    unsafe {
+        core::arch::asm!("mrc p15, #0, {}, c9, c12, #0", out(reg) pmcr);
+        core::arch::asm!("mrc p15, #0, {}, c9, c12, #1", out(reg) pmcntenset);
+    }
+    (pmcr, pmcntenset)
+}
+
+/// Put back PMCR and PMCNTENSET.C as `read_cycle_counter_config()` found them,
+/// without resetting any of the counters.
+#[cfg(not(feature = "sim"))]
+pub unsafe fn write_cycle_counter_config(pmcr: u32, pmcntenset: u32) {
+    unsafe {
+        core::arch::asm!("mcr p15, #0, {}, c9, c12, #0", in(reg) pmcr & !PMCR_RESETS);
+        if pmcntenset & PMCNTENSET_C == 0 {
+            // PMCNTENCLR
+            core::arch::asm!("mcr p15, #0, {}, c9, c12, #2", in(reg) PMCNTENSET_C);
+        }
+    }
+}
+
+/// Start the PMU cycle counter if it isn't already running. This sets PMCR.E and
+/// PMCNTENSET.C without resetting the counter. PMCR.D is cleared too, since a
+/// divider left on by the application would make every deadline 64 times longer.
+/// Use `read_cycle_counter_config()` beforehand to put them back afterwards.
+#[cfg(not(feature = "sim"))]
+pub unsafe fn enable_cycle_counter() {
+    unsafe {
+        let (pmcr, _) = read_cycle_counter_config();
+        let pmcr = (pmcr | PMCR_E) & !(PMCR_D | PMCR_RESETS);
+        core::arch::asm!("mcr p15, #0, {}, c9, c12, #0", in(reg) pmcr);
+        core::arch::asm!("mcr p15, #0, {}, c9, c12, #1", in(reg) PMCNTENSET_C);
+    }
+}
+
+/// Read the PMU cycle counter, which counts CPU clock cycles.
+#[cfg(not(feature = "sim"))]
+pub unsafe fn read_cycle_counter() -> u32 {
+    let cycles: u32;
+    unsafe {
+        core::arch::asm!("mrc p15, #0, {}, c9, c13, #0", out(reg) cycles);
+    }
+    cycles
+}
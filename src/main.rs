@@ -1,351 +1,300 @@
-#![no_std]
-#![no_main]
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
 
-use f021::{FlashBank, FsmStatus};
+use backend::{F021, FlashBackend};
+use core::ops::Range;
+use f021::{FlashBank, FlashBankTech};
 use flash_algorithm::{ErrorCode, FlashAlgorithm, Function};
-use rtt_target::{rprint, rprintln, rtt_init_print};
+use rtt_target::{rprint, rprintln};
+use timeout::Deadline;
 
 // Import the `cortex_ar` crate. This is necessary to tell the compiler that we do
 // use this crate, even if we don't call any functions from inside it. Without this,
 // critical section code would be unavailable, leading to opaque errors such as
 // `undefined symbol: _critical_section_1_0_acquire`.
+#[cfg(target_arch = "arm")]
 use cortex_ar as _;
 
+mod backend;
+mod checksum;
+mod clock;
+mod device;
 mod f021;
+mod routines;
+#[cfg(all(test, feature = "sim"))]
+mod tests;
+mod timeout;
+mod wait_states;
 
 /// Set this to `true` in order to print sector information for inclusion
-/// in the `flash_algorithm::algorithm!()` definition.
+/// in the `flash_algorithm::algorithm!()` definition in the device profile.
 const PRINT_SECTOR_INFORMATION: bool = false;
 
-/// Set to `true` to print version information on init.
-const PRINT_VERSION_INFORMATION: bool = false;
+/// The most bytes that the flash API can take at once, which is a row of the
+/// widest bank. Each bank is programmed a row at a time, as `Sector::row_width`.
+const WRITE_BLOCK_SIZE: usize = device::FLEP_ROW_WIDTH;
+const _: () = assert!(device::FLEE_ROW_WIDTH <= WRITE_BLOCK_SIZE);
 
-/// The number of bytes that the flash API can take at once.
-const WRITE_BLOCK_SIZE: usize = 32;
+/// The largest page that probe-rs passes to `ProgramPage()`, which is the main
+/// flash page of the TMS570LS31x.
+const MAX_PAGE_SIZE: usize = 32 * 1024;
 
-/// HCLK comes from OSCIN by default, which is a 16 MHz crystal on Launch-XL2
-const DEFAULT_CLOCK: u32 = 16;
+/// The narrowest row that the algorithm allows for, which is one 64-bit word.
+const MIN_ROW_WIDTH: usize = 8;
 
-/// The default EWAIT is fine for 16 MHz clock
-const DEFAULT_EWAIT: Option<u32> = None;
+/// The most rows that a page can span, at the narrowest row width. One bit is
+/// kept for each to record the rows that need programming.
+const MAX_PAGE_ROWS: usize = MAX_PAGE_SIZE / MIN_ROW_WIDTH + 1;
 
-/// Read value comes from datasheet
-const DEFAULT_RWAIT: Option<u32> = Some(3);
+/// HCLK comes from OSCIN by default, which is a 16 MHz crystal on Launch-XL2. This
+/// is used if probe-rs doesn't pass a clock and it can't be worked out either.
+const DEFAULT_CLOCK: u32 = 16;
 
 /// When performing a blank check, we limit the amount of data we process at
 /// a time in order to make retries less catastrophic.
 const BLANK_CHECK_BYTE_COUNT: u32 = 1024;
 
-/// Due to being unable to disable the ECC on the TMS570, we need to try
-/// multiple times in the event of a failure.
+/// Due to being unable to disable the ECC on the TMS570LC43x, we need to try
+/// multiple times in the event of a failure. This only applies to blank checks
+/// that use the flash API.
 const BLANK_CHECK_RETRIES: usize = 6;
 
-// A collection of registers
-
-const FBPWRMODE: *mut u32 = 0xfff8_7040 as *mut u32;
-const EWAIT: *mut u32 = 0xfff8_72b8 as *mut u32;
-const FSM_WR_ENA: *mut u32 = 0xfff8_7288 as *mut u32;
-const FRDCNTL: *mut u32 = 0xfff8_7000 as *mut u32;
-
-const SECTORS: &[FlashSector] = &[
-    // FlashSector {
-    //     address: 0,
-    //     size: 0x00200000,
-    // },
-    // FlashSector {
-    //     address: 0x00200000,
-    //     size: 0x00200000,
-    // },
-    // Bank 0
-    FlashSector {
-        size: 0x4000,
-        address: 0x0,
-    },
-    FlashSector {
-        size: 0x4000,
-        address: 0x4000,
-    },
-    FlashSector {
-        size: 0x4000,
-        address: 0x8000,
-    },
-    FlashSector {
-        size: 0x4000,
-        address: 0xc000,
-    },
-    FlashSector {
-        size: 0x4000,
-        address: 0x10000,
-    },
-    FlashSector {
-        size: 0x4000,
-        address: 0x14000,
-    },
-    FlashSector {
-        size: 0x8000,
-        address: 0x18000,
-    },
-    FlashSector {
-        size: 0x20000,
-        address: 0x20000,
-    },
-    FlashSector {
-        size: 0x20000,
-        address: 0x40000,
-    },
-    FlashSector {
-        size: 0x20000,
-        address: 0x60000,
-    },
-    FlashSector {
-        size: 0x40000,
-        address: 0x80000,
-    },
-    FlashSector {
-        size: 0x40000,
-        address: 0xc0000,
-    },
-    FlashSector {
-        size: 0x40000,
-        address: 0x100000,
-    },
-    FlashSector {
-        size: 0x40000,
-        address: 0x140000,
-    },
-    FlashSector {
-        size: 0x40000,
-        address: 0x180000,
-    },
-    FlashSector {
-        size: 0x40000,
-        address: 0x1c0000,
-    },
-    // Bank 1
-    FlashSector {
-        size: 0x20000,
-        address: 0x200000,
-    },
-    FlashSector {
-        size: 0x20000,
-        address: 0x220000,
-    },
-    FlashSector {
-        size: 0x20000,
-        address: 0x240000,
-    },
-    FlashSector {
-        size: 0x20000,
-        address: 0x260000,
-    },
-    FlashSector {
-        size: 0x20000,
-        address: 0x280000,
-    },
-    FlashSector {
-        size: 0x20000,
-        address: 0x2a0000,
-    },
-    FlashSector {
-        size: 0x20000,
-        address: 0x2c0000,
-    },
-    FlashSector {
-        size: 0x20000,
-        address: 0x2e0000,
-    },
-    FlashSector {
-        size: 0x20000,
-        address: 0x300000,
-    },
-    FlashSector {
-        size: 0x20000,
-        address: 0x320000,
-    },
-    FlashSector {
-        size: 0x20000,
-        address: 0x340000,
-    },
-    FlashSector {
-        size: 0x20000,
-        address: 0x360000,
-    },
-    FlashSector {
-        size: 0x20000,
-        address: 0x380000,
-    },
-    FlashSector {
-        size: 0x20000,
-        address: 0x3a0000,
-    },
-    FlashSector {
-        size: 0x20000,
-        address: 0x3c0000,
-    },
-    FlashSector {
-        size: 0x20000,
-        address: 0x3e0000,
+/// Pass this as the `address` argument to `Init()` along with `Function::Program`
+/// in order to allow the customer OTP to be programmed.
+const OTP_UNLOCK_KEY: u32 = 0x4f54_5055;
+
+/// probe-rs always passes the start of the flash region to `Init()`, so it can't
+/// pass `OTP_UNLOCK_KEY`. Writing the key to this address before `Init()` unlocks
+/// the OTP instead. The key is cleared by the `Program` session that uses it, so it
+/// only unlocks one download. This is just after the protection override.
+const OTP_UNLOCK_ADDRESS: u32 = PROTECTION_OVERRIDE_ADDRESS + 9 * 4;
+
+/// Sectors that are never erased or programmed, as one mask per bank with bit `n`
+/// set to protect sector `n`. For example, `[0b111, 0, 0, 0, 0, 0, 0, 0]` protects
+/// a bootloader in the first three sectors of bank 0.
+const PROTECTED_SECTORS: [u32; 8] = [0; 8];
+
+/// `PROTECTED_SECTORS` can be replaced without rebuilding the algorithm by writing
+/// `PROTECTION_OVERRIDE_MAGIC` to this address before calling `Init()`, followed
+/// by the eight masks to use instead.
+const PROTECTION_OVERRIDE_ADDRESS: u32 = 0x0800_0000;
+const PROTECTION_OVERRIDE_MAGIC: u32 = 0x5052_4f54;
+
+/// Where the bootloader looks for the application header that `ValidateImage()`
+/// checks. This assumes a bootloader in the first 128 KB of flash, followed by the
+/// application with its header at the start.
+const APP_HEADER_ADDRESS: u32 = 0x0002_0000;
+const APP_HEADER_MAGIC: u32 = 0x4150_5048;
+
+struct Algorithm<B: FlashBackend = F021> {
+    backend: B,
+    /// HCLK in MHz, which FSM deadlines are measured in
+    clock: u32,
+    /// The state of the flash controller before `new()` initialized it, or `None`
+    /// if it was left alone because the algorithm is only verifying
+    saved_state: Option<B::State>,
+    /// `true` if `new()` was given `OTP_UNLOCK_KEY`, either directly or through
+    /// `OTP_UNLOCK_ADDRESS`
+    otp_unlocked: bool,
+    /// One mask of protected sectors per bank, as in `PROTECTED_SECTORS`
+    protected_sectors: [u32; 8],
+    /// The PMU as the application left it, before FSM deadlines started the cycle
+    /// counter
+    cycle_counter: f021::CycleCounterConfig,
+}
+
+/// Errors generated by the algorithm itself rather than by the F021 API.
+///
+/// These are encoded as `0xCCLLLLRR`, where `CC` is the category, `LLLL` is the
+/// location that failed, and `RR` is the reason. For programming errors the
+/// location is the byte offset into the page. Verify and blank check ranges can be
+/// much larger than a page, so for those the reason holds bits 16-23 of the
+/// offset, and `LLLLRR` is the offset with its low 16 bits first.
+/// For protected sectors it is the bank in the upper byte and the sector in the
+/// lower byte. For FSM timeouts it is FMSTAT, and the reason is the operation.
+enum AlgorithmError {
+    /// The F021 API refused to program the chunk at `offset`.
+    ProgramCommand { offset: u32, error: f021::Error },
+    /// The FSM reported a failure after programming the chunk at `offset`.
+    ProgramStatus { offset: u32, error: f021::FsmError },
+    /// The FSM reported a failure after erasing a sector.
+    EraseSector(f021::FsmError),
+    /// The contents of flash did not match at `offset` into the verified range.
+    VerifyMismatch { offset: u32 },
+    /// The customer OTP was not unlocked when the algorithm was initialized.
+    OtpLocked,
+    /// The customer OTP at `offset` into the page has already been programmed
+    /// with a different value.
+    OtpProgrammed { offset: u32 },
+    /// The sector is protected by `PROTECTED_SECTORS` or its override.
+    SectorProtected { bank: FlashBank, sector: usize },
+    /// The flash at `offset` into the blank checked range is not erased.
+    NotBlank { offset: u32 },
+    /// The byte at `offset` into the page reads differently under margin 0 and
+    /// margin 1, so some of its bits are only just programmed or erased.
+    MarginMismatch { offset: u32 },
+    /// The FSM was still busy with `operation` after the datasheet's worst-case time.
+    FsmTimeout {
+        operation: FsmOperation,
+        fmstat: u32,
     },
-];
+    /// There is no valid application header where `ValidateImage()` looked.
+    NoImageHeader,
+    /// The checksum of the image doesn't match the one in its header.
+    ChecksumMismatch,
+    /// The byte at `offset` into the page would need a bit to go from 0 to 1, which
+    /// only an erase can do.
+    NeedsErase { offset: u32 },
+    /// `Verify()` was called without any data to compare the flash against.
+    NoVerifyData,
+    /// `Checksum()` was asked for a kind of checksum that it doesn't know.
+    UnknownChecksumKind { kind: u32 },
+}
 
-struct Algorithm;
+impl From<AlgorithmError> for ErrorCode {
+    fn from(val: AlgorithmError) -> Self {
+        let (category, location, reason) = match val {
+            AlgorithmError::ProgramCommand { offset, error } => {
+                (0x04, offset, Into::<ErrorCode>::into(error).get() & 0xff)
+            }
+            AlgorithmError::ProgramStatus { offset, error } => (0x05, offset, error.into()),
+            AlgorithmError::EraseSector(error) => (0x06, 0, error.into()),
+            AlgorithmError::VerifyMismatch { offset } => (0x08, offset, (offset >> 16) & 0xff),
+            AlgorithmError::OtpLocked => (0x09, 0, 0),
+            AlgorithmError::OtpProgrammed { offset } => (0x0a, offset, 0),
+            AlgorithmError::SectorProtected { bank, sector } => {
+                (0x0b, ((bank as u32) << 8) | sector as u32, 0)
+            }
+            AlgorithmError::NotBlank { offset } => (0x0d, offset, (offset >> 16) & 0xff),
+            AlgorithmError::MarginMismatch { offset } => (0x0e, offset, 0),
+            AlgorithmError::FsmTimeout { operation, fmstat } => (0x0c, fmstat, operation as u32),
+            AlgorithmError::NoImageHeader => (0x0f, 0, 0),
+            AlgorithmError::ChecksumMismatch => (0x10, 0, 0),
+            AlgorithmError::NeedsErase { offset } => (0x11, offset, 0),
+            AlgorithmError::NoVerifyData => (0x12, 0, 0),
+            AlgorithmError::UnknownChecksumKind { kind } => (0x13, 0, kind & 0xff),
+        };
+        ErrorCode::new((category << 24) | ((location & 0xffff) << 8) | reason).unwrap()
+    }
+}
 
-fn bank_for_address(address: u32) -> FlashBank {
-    if address > 0xf0200000 {
-        FlashBank::_7
-    } else if address > 0x200000 {
-        FlashBank::_1
-    } else {
-        FlashBank::_0
+/// The FSM operations that the algorithm waits for, each with its own deadline.
+#[derive(Debug, Clone, Copy)]
+enum FsmOperation {
+    /// Activity that the algorithm didn't start, such as an erase started by the
+    /// application, which is given as long as the slowest operation
+    Other = 0,
+    Program = 1,
+    EraseSector = 2,
+}
+
+impl FsmOperation {
+    /// The longest that the FSM may take to finish this operation.
+    fn timeout_us(self) -> u32 {
+        match self {
+            FsmOperation::Program => device::PROGRAM_TIMEOUT_US,
+            FsmOperation::EraseSector => device::SECTOR_ERASE_TIMEOUT_US,
+            FsmOperation::Other => device::BANK_ERASE_TIMEOUT_US,
+        }
+    }
+}
+
+/// A sector as reported by the F021 API.
+struct Sector {
+    bank: FlashBank,
+    /// `true` if this sector is in an EEPROM emulation (FLEE) bank
+    eeprom: bool,
+    index: usize,
+    address: u32,
+    size: u32,
+    /// The number of data bytes in one row of this sector's bank
+    row_width: usize,
+}
+
+/// The number of rows `width` bytes wide that `length` bytes starting `lead`
+/// bytes into a row touch.
+fn row_count(lead: usize, width: usize, length: usize) -> usize {
+    (lead + length).div_ceil(width)
+}
+
+/// The part of those `length` bytes that falls in row `index`, where only the
+/// first and last rows can be partial.
+fn row_range(index: usize, lead: usize, width: usize, length: usize) -> Range<usize> {
+    (index * width).saturating_sub(lead)..((index + 1) * width - lead).min(length)
+}
+
+/// What programming part of a 64-bit flash word would take.
+enum WordState {
+    /// The flash already holds the data and its ECC.
+    Programmed,
+    /// The data, its ECC or both can be programmed without an erase.
+    Programmable,
+    /// The word has to be erased first. This holds the first address that differs.
+    NeedsErase(u32),
+}
+
+/// Return the sectors to protect: the override in RAM if one is present, and
+/// `PROTECTED_SECTORS` otherwise.
+fn protected_sectors() -> [u32; 8] {
+    let magic = PROTECTION_OVERRIDE_ADDRESS as *mut u32;
+    if f021::read_register(magic) != PROTECTION_OVERRIDE_MAGIC {
+        return PROTECTED_SECTORS;
     }
+    let mut protected_sectors = [0; 8];
+    for (index, mask) in protected_sectors.iter_mut().enumerate() {
+        *mask = f021::read_register(magic.wrapping_add(1 + index));
+    }
+    protected_sectors
+}
+
+/// Return `true` if the customer OTP has been unlocked through `OTP_UNLOCK_ADDRESS`,
+/// and clear the key so that it isn't used again.
+fn take_otp_unlock_key() -> bool {
+    let key = OTP_UNLOCK_ADDRESS as *mut u32;
+    if f021::read_register(key) != OTP_UNLOCK_KEY {
+        return false;
+    }
+    f021::write_register(key, 0);
+    true
+}
+
+/// Return the bank whose customer OTP contains `address`, if any.
+fn otp_bank_for_address(address: u32) -> Option<FlashBank> {
+    let offset = address.checked_sub(device::OTP_ADDRESS)?;
+    if offset >= device::OTP_WINDOW_SIZE {
+        return None;
+    }
+    FlashBank::try_from((offset / device::OTP_BANK_SIZE) as u16).ok()
+}
+
+/// Return the main flash address whose ECC lives at `address`, if `address` is
+/// in the ECC mirror. There is one ECC byte for every 64-bit word of flash.
+fn ecc_data_address(address: u32) -> Option<u32> {
+    let offset = address.checked_sub(device::ECC_ADDRESS)?;
+    if offset >= device::ECC_SIZE {
+        return None;
+    }
+    Some(offset * 8)
 }
 
-flash_algorithm::algorithm!(Algorithm, {
-    device_name: "f021",
-    device_type: DeviceType::Onchip,
-    flash_address: 0,
-    flash_size: (4*1024*1024),
-    // Note that the page size is effectively the same as
-    // the sector size due to how the API works. The value
-    // presented here is simply the smallest sector size.
-    page_size: (16*1024),
-    empty_value: 0xFFu8,
-    program_time_out: 2000,
-    erase_time_out: 14000,
-    sectors: [
-        // Bank 0
-        {
-            size: 0x4000u32,
-            address: 0x0u32,
-        },
-        {
-            size: 0x4000u32,
-            address: 0x4000u32,
-        },
-        {
-            size: 0x4000u32,
-            address: 0x8000u32,
-        },
-        {
-            size: 0x4000u32,
-            address: 0xc000u32,
-        },
-        {
-            size: 0x4000u32,
-            address: 0x10000u32,
-        },
-        {
-            size: 0x4000u32,
-            address: 0x14000u32,
-        },
-        {
-            size: 0x8000u32,
-            address: 0x18000u32,
-        },
-        {
-            size: 0x20000u32,
-            address: 0x20000u32,
-        },
-        {
-            size: 0x20000u32,
-            address: 0x40000u32,
-        },
-        {
-            size: 0x20000u32,
-            address: 0x60000u32,
-        },
-        {
-            size: 0x40000u32,
-            address: 0x80000u32,
-        },
-        {
-            size: 0x40000u32,
-            address: 0xc0000u32,
-        },
-        {
-            size: 0x40000u32,
-            address: 0x100000u32,
-        },
-        {
-            size: 0x40000u32,
-            address: 0x140000u32,
-        },
-        {
-            size: 0x40000u32,
-            address: 0x180000u32,
-        },
-        {
-            size: 0x40000u32,
-            address: 0x1c0000u32,
-        },
-        // Bank 1
-        {
-            size: 0x20000u32,
-            address: 0x200000u32,
-        },
-        {
-            size: 0x20000u32,
-            address: 0x220000u32,
-        },
-        {
-            size: 0x20000u32,
-            address: 0x240000u32,
-        },
-        {
-            size: 0x20000u32,
-            address: 0x260000u32,
-        },
-        {
-            size: 0x20000u32,
-            address: 0x280000u32,
-        },
-        {
-            size: 0x20000u32,
-            address: 0x2a0000u32,
-        },
-        {
-            size: 0x20000u32,
-            address: 0x2c0000u32,
-        },
-        {
-            size: 0x20000u32,
-            address: 0x2e0000u32,
-        },
-        {
-            size: 0x20000u32,
-            address: 0x300000u32,
-        },
-        {
-            size: 0x20000u32,
-            address: 0x320000u32,
-        },
-        {
-            size: 0x20000u32,
-            address: 0x340000u32,
-        },
-        {
-            size: 0x20000u32,
-            address: 0x360000u32,
-        },
-        {
-            size: 0x20000u32,
-            address: 0x380000u32,
-        },
-        {
-            size: 0x20000u32,
-            address: 0x3a0000u32,
-        },
-        {
-            size: 0x20000u32,
-            address: 0x3c0000u32,
-        },
-        {
-            size: 0x20000u32,
-            address: 0x3e0000u32,
-        } // Must skip final `,`
-    ]
-});
+/// Return the address in the ECC mirror of the ECC for the main flash word at
+/// `data_address`. This is the reverse of `ecc_data_address()`.
+fn ecc_address(data_address: u32) -> Option<u32> {
+    let offset = data_address / 8;
+    (offset < device::ECC_SIZE).then(|| device::ECC_ADDRESS + offset)
+}
 
+/// The mode used to program pages of main flash. With the `image-ecc` feature the
+/// ECC comes from the image itself and is programmed through the ECC mirror.
+fn data_programming_mode() -> f021::FlashProgrammingCommand {
+    if cfg!(feature = "image-ecc") {
+        f021::FlashProgrammingCommand::DataOnly
+    } else {
+        f021::FlashProgrammingCommand::AutoEccGeneration
+    }
+}
+
+#[cfg(not(any(test, feature = "sim")))]
 #[inline(never)]
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
@@ -356,73 +305,626 @@ fn panic(info: &core::panic::PanicInfo) -> ! {
     }
 }
 
-impl FlashAlgorithm for Algorithm {
-    fn new(_address: u32, mut clock: u32, _function: Function) -> Result<Self, ErrorCode> {
-        f021::invalidate_caches();
-        rtt_init_print!(rtt_target::ChannelMode::BlockIfFull, 256);
+impl<B: FlashBackend> Algorithm<B> {
+    /// Iterate over every bank that the backend has a sector layout for. Banks are
+    /// numbered up to 7 whatever the device reports as its number of banks, which
+    /// needn't count the EEPROM emulation bank.
+    fn flash_banks(&self) -> impl Iterator<Item = (FlashBank, f021::FlashBankSectors)> + '_ {
+        (0..8u16).filter_map(|bank_number| {
+            let bank = FlashBank::try_from(bank_number).ok()?;
+            self.backend
+                .bank_sectors(bank)
+                .ok()
+                .map(|sectors| (bank, sectors))
+        })
+    }
 
-        if clock == 0 {
+    /// Iterate over the banks that this build of the algorithm exports to probe-rs:
+    /// the EEPROM emulation banks with the `eeprom` feature, none with the `otp` or
+    /// `ecc` features, since neither can be erased on its own, and the main banks
+    /// otherwise.
+    fn algorithm_flash_banks(
+        &self,
+    ) -> impl Iterator<Item = (FlashBank, f021::FlashBankSectors)> + '_ {
+        self.flash_banks().filter(|(_, sectors)| {
+            !cfg!(any(feature = "otp", feature = "ecc"))
+                && (sectors.flash_bank_tech == FlashBankTech::FLEE) == cfg!(feature = "eeprom")
+        })
+    }
+
+    /// The number of data bytes in one row of the main flash banks, or of the EEPROM
+    /// emulation bank if `eeprom` is `true`. This comes from the bank geometry, but is
+    /// never more than the device profile allows for, since that sizes the buffers.
+    fn row_width(&self, eeprom: bool) -> usize {
+        let limit = if eeprom {
+            device::FLEE_ROW_WIDTH
+        } else {
+            device::FLEP_ROW_WIDTH
+        };
+        (self.backend.row_width(eeprom) & !(MIN_ROW_WIDTH - 1)).clamp(MIN_ROW_WIDTH, limit)
+    }
+
+    /// Find the sector that contains `address` in any of the flash banks.
+    fn sector_for_address(&self, address: u32) -> Option<Sector> {
+        for (bank, bank_sectors) in self.flash_banks() {
+            let mut start = bank_sectors.bank_start_address;
+            for (index, &size) in bank_sectors.sector_sizes().iter().enumerate() {
+                if address >= start && address < start + size {
+                    let eeprom = bank_sectors.flash_bank_tech == FlashBankTech::FLEE;
+                    return Some(Sector {
+                        bank,
+                        eeprom,
+                        index,
+                        address: start,
+                        size,
+                        row_width: self.row_width(eeprom),
+                    });
+                }
+                start += size;
+            }
+        }
+        None
+    }
+
+    /// Wait for the FSM to finish `operation`. If it is still busy after the
+    /// datasheet's worst-case time for that operation, clear the FSM and return
+    /// an error instead of waiting forever.
+    fn wait_for_fsm(&self, operation: FsmOperation) -> Result<(), ErrorCode> {
+        let mut deadline = Deadline::after(operation.timeout_us(), self.clock);
+        loop {
+            let status = self.backend.fsm_status();
+            if !status.busy() {
+                return Ok(());
+            }
+            if deadline.expired() {
+                rprintln!("Timed out waiting for {:?} -- {:?}", operation, status);
+                if let Err(e) = self.backend.clear_more() {
+                    rprintln!("Unable to clear the FSM: {}", e);
+                }
+                self.clear_status();
+                self.backend.flush();
+                return Err(AlgorithmError::FsmTimeout {
+                    operation,
+                    fmstat: status.raw(),
+                }
+                .into());
+            }
+        }
+    }
+
+    /// Program `data` and `ecc` to `address` and check the FSM status once it completes.
+    /// `offset` is the offset into the page being programmed, which is used for error
+    /// reporting. The active bank must already be set up.
+    fn program_chunk(
+        &self,
+        address: u32,
+        offset: u32,
+        data: &[u8],
+        ecc: Option<&[u8]>,
+        mode: f021::FlashProgrammingCommand,
+    ) -> Result<(), ErrorCode> {
+        if let Err(e) = self.backend.program(address, data, ecc, mode) {
+            rprintln!("Unable to program 0x{:08x}: {}", address, e);
+            self.backend.flush();
+            return Err(AlgorithmError::ProgramCommand { offset, error: e }.into());
+        }
+
+        self.wait_for_fsm(FsmOperation::Program)?;
+
+        let status = self.backend.fsm_status();
+        if let Some(error) = status.error() {
             rprintln!(
-                "Clock was detected as 0 -- setting to {} MHz",
-                DEFAULT_CLOCK
+                "Programming 0x{:08x} failed: {} -- {:?}",
+                address,
+                error,
+                status
             );
-            clock = DEFAULT_CLOCK;
+            self.backend.flush();
+            return Err(AlgorithmError::ProgramStatus { offset, error }.into());
         }
-        rprintln!("Initializing FAPI...");
+        Ok(())
+    }
 
-        // Ensure the EEPROM is powered up, since that is read by `initialize_flash_banks()`.
-        let fbpwrmode = unsafe { FBPWRMODE.read_volatile() };
-        if fbpwrmode & 0xffff != 0xffff {
-            rprintln!("FBPWRMODE was {:04x} and not 0xffff -- fixing", fbpwrmode);
-            unsafe {
-                FBPWRMODE.write_volatile(0x0505_ffff);
+    /// Return `true` if sector `index` of `bank_number` must not be erased or programmed.
+    fn sector_protected(&self, bank_number: FlashBank, index: usize) -> bool {
+        index < 32 && self.protected_sectors[bank_number as usize] & (1 << index) != 0
+    }
+
+    /// Return an error if `sector` must not be erased or programmed.
+    fn check_protection(&self, sector: &Sector) -> Result<(), ErrorCode> {
+        if self.sector_protected(sector.bank, sector.index) {
+            rprintln!(
+                "Bank {:?}, Sector {} at 0x{:08x} is protected",
+                sector.bank,
+                sector.index,
+                sector.address
+            );
+            return Err(AlgorithmError::SectorProtected {
+                bank: sector.bank,
+                sector: sector.index,
             }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Enable every unprotected sector of the active bank for erasing and programming.
+    fn enable_sectors(&self, bank_number: FlashBank, eeprom: bool) -> Result<(), ErrorCode> {
+        let enables = !(self.protected_sectors[bank_number as usize] as u64);
+        if let Err(e) = self.backend.enable_sectors(eeprom, enables) {
+            rprintln!("Unable to enable sectors for bank {:?}: {}", bank_number, e);
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    /// Erase the sector containing `addr` and check the FSM status once it completes.
+    /// The active bank must already be set up.
+    fn erase_sector_at(&self, addr: u32) -> Result<(), ErrorCode> {
+        if let Err(e) = self.backend.erase_sector(addr) {
+            rprintln!("Unable to erase sector: {}", e);
+            return Err(e.into());
+        }
+
+        self.wait_for_fsm(FsmOperation::EraseSector)?;
+
+        self.backend.flush();
+
+        let status = self.backend.fsm_status();
+        if let Some(error) = status.error() {
+            rprintln!("Erasing sector failed: {} -- {:?}", error, status);
+            return Err(AlgorithmError::EraseSector(error).into());
+        }
+        Ok(())
+    }
+
+    /// Finish an erase that was suspended, such as by an earlier session that was
+    /// cancelled, so that the sector isn't left partially erased.
+    fn resume_erase(&self) -> Result<(), ErrorCode> {
+        if !self.backend.fsm_status().esusp() {
+            return Ok(());
+        }
+        rprintln!("Resuming a suspended erase");
+        if let Err(e) = self.backend.resume_erase() {
+            rprintln!("Unable to resume erase: {}", e);
+            return Err(e.into());
         }
 
-        if let Some(ewait) = DEFAULT_EWAIT {
-            rprintln!("Setting EWAIT to {}", ewait);
-            unsafe {
-                FSM_WR_ENA.write_volatile(5);
-                EWAIT.write_volatile((ewait & 15) << 16);
-                FSM_WR_ENA.write_volatile(2);
+        self.wait_for_fsm(FsmOperation::EraseSector)?;
+
+        self.backend.flush();
+
+        let status = self.backend.fsm_status();
+        if let Some(error) = status.error() {
+            rprintln!("Resumed erase failed: {} -- {:?}", error, status);
+            return Err(AlgorithmError::EraseSector(error).into());
+        }
+        Ok(())
+    }
+
+    /// Check that `size` bytes of flash at `address` all read as `pattern`, with ECC
+    /// checking turned off. Unlike `Fapi_doBlankCheck()`, this can't be upset by the
+    /// ECC logic "correcting" erased flash, so one pass is enough. Returns the first
+    /// address that doesn't match.
+    ///
+    /// Turning ECC checking off only reaches flash on the ATCM, which is where main
+    /// flash is on the TMS570LS31x. The TMS570LC43x checks the ECC of main flash in
+    /// the L2 memory interface, where it can't be turned off, so erased main flash
+    /// is only checked this way on the TMS570LS31x.
+    fn blank_check_by_reading(&self, address: u32, size: u32, pattern: u8) -> Result<(), u32> {
+        let end = address + size;
+        let mut buffer = [0u8; WRITE_BLOCK_SIZE];
+        let mut chunk = address;
+        while chunk < end {
+            let data = &mut buffer[..(end - chunk).min(WRITE_BLOCK_SIZE as u32) as usize];
+            self.backend.read_without_ecc(chunk, data);
+            if let Some(offset) = data.iter().position(|&b| b != pattern) {
+                return Err(chunk + offset as u32);
             }
+            chunk += data.len() as u32;
         }
+        Ok(())
+    }
 
-        if let Some(rwait) = DEFAULT_RWAIT {
-            unsafe {
-                let frdcntl = FRDCNTL.read_volatile();
-                rprintln!("Current RWAIT: {}", (frdcntl >> 8) & 15);
-                let frdcntl = (frdcntl & !(15 << 8)) | ((rwait & 15) << 8) | 0b1 | 0b10;
-                rprintln!("Setting RWAIT to {}", rwait);
-                FRDCNTL.write_volatile(frdcntl);
+    /// Check that the ECC of the 64-bit words of main flash in `size` bytes at
+    /// `address` is erased, which is read through the ECC mirror. Returns the start
+    /// of the first word whose ECC isn't blank.
+    fn blank_check_ecc(&self, address: u32, size: u32) -> Result<(), u32> {
+        let end = address + size;
+        let mut buffer = [0u8; WRITE_BLOCK_SIZE];
+        // Only words that are entirely within the range are checked, since the ECC
+        // of the others also covers data outside of it.
+        let mut word = address.next_multiple_of(8);
+        while word + 8 <= end {
+            let Some(ecc) = ecc_address(word) else {
+                break;
+            };
+            let ecc_bytes = &mut buffer[..((end - word) / 8).min(WRITE_BLOCK_SIZE as u32) as usize];
+            self.backend.read(ecc, ecc_bytes);
+            if let Some(index) = ecc_bytes.iter().position(|&b| b != 0xff) {
+                return Err(word + index as u32 * 8);
             }
+            word += ecc_bytes.len() as u32 * 8;
+        }
+        Ok(())
+    }
+
+    /// Work out what programming `data` at `address` would take, where `data` is
+    /// the part of the page that falls in the flash word at `word`.
+    fn word_state(&self, eeprom: bool, word: u32, address: u32, data: &[u8]) -> WordState {
+        let image_ecc = cfg!(feature = "image-ecc");
+        let ecc = (!eeprom).then(|| ecc_address(word)).flatten().map(|ecc| {
+            let mut byte = [0u8];
+            self.backend.read(ecc, &mut byte);
+            byte[0]
+        });
+
+        // The ECC of main flash on the TMS570LC43x covers the address, so reading a
+        // word whose ECC is erased raises an error. Those words are compared by the
+        // flash API instead.
+        if !eeprom && !cfg!(feature = "ls3137") && (ecc == Some(0xff) || image_ecc) {
+            return match self.backend.verify_bytewise(address, data) {
+                Ok(()) if image_ecc => WordState::Programmed,
+                Ok(()) => WordState::Programmable,
+                Err(_) if self.backend.blank_check(word, 8).is_ok() => WordState::Programmable,
+                Err(status) => WordState::NeedsErase(status.non_blank_address),
+            };
+        }
+
+        let mut current = [0u8; 8];
+        if eeprom || cfg!(feature = "ls3137") {
+            self.backend.read_without_ecc(word, &mut current);
+        } else {
+            self.backend.read(word, &mut current);
+        }
+        let blank = current == [0xff; 8];
+        // There is no mirror for the ECC of the FLEE bank, so an erased word is
+        // taken to have erased ECC too
+        let ecc_erased = ecc.map_or(blank, |ecc| ecc == 0xff);
+        let offset = (address - word) as usize;
+        let mismatch = current[offset..]
+            .iter()
+            .zip(data)
+            .position(|(current, new)| current != new);
+        match mismatch {
+            None if ecc_erased && !image_ecc => WordState::Programmable,
+            None => WordState::Programmed,
+            Some(_) if blank && (ecc_erased || image_ecc) => WordState::Programmable,
+            Some(position) => WordState::NeedsErase(address + position as u32),
+        }
+    }
+
+    /// Check that `size` bytes at `address` are erased using the flash API. The
+    /// unaligned head and tail of the range are checked one byte at a time, and the
+    /// words in between in chunks of `BLANK_CHECK_BYTE_COUNT`. Returns the first
+    /// address that isn't blank.
+    fn blank_check_with_api(&self, address: u32, size: u32) -> Result<(), u32> {
+        let head = (address.next_multiple_of(4) - address).min(size);
+        let tail = (size - head) % 4;
+        let words_end = address + size - tail;
+
+        self.blank_check_with_retries(address, head, true)?;
+        let mut chunk = address + head;
+        while chunk < words_end {
+            let to_check = (words_end - chunk).min(BLANK_CHECK_BYTE_COUNT);
+            self.blank_check_with_retries(chunk, to_check, false)?;
+            chunk += to_check;
+        }
+        self.blank_check_with_retries(words_end, tail, true)
+    }
+
+    /// Run one blank check through the flash API, either a word or a byte at a time,
+    /// and return the first address that isn't blank. When the API fails without
+    /// saying where, such as for an address outside of the banks, this is `address`.
+    fn blank_check_with_retries(&self, address: u32, size: u32, bytewise: bool) -> Result<(), u32> {
+        if size == 0 {
+            return Ok(());
         }
 
-        rprintln!("Calling initialize_flash_banks({})...", clock);
-        if let Err(e) = f021::initialize_flash_banks(clock) {
+        // Check multiple times. Strictly speaking, the "blank check" function is
+        // not supposed to be used on banks 0 or 1 because an erased flash contains
+        // errors, and those errors will sometimes get corrected by the machinery,
+        // resulting in blank check failures. However, performing the check multiple
+        // times appears to work.
+        //
+        // See https://e2e.ti.com/support/microcontrollers/arm-based-microcontrollers-group/arm-based-microcontrollers/f/arm-based-microcontrollers-forum/1116947/tms570lc4357-sw-stuck-in-fapi_doblankcheck-when-trying-to-update-fee-in-case-of-freertos/4139678#4139678
+        //
+        //  > Hi Sakti,
+        //  > Fapi_doBlankCheck() is to check the erase state of flash bank. As the erase
+        //  > state of the Flash is not a valid ECC condition, the ECC check and correction
+        //  > must be disabled. But the flash ECC on TMS570LC43x is enabled by default and
+        //  > can not be disabled. We don't suggest using this function in your project.
+        let mut result = Ok(());
+        for try_number in 0..BLANK_CHECK_RETRIES {
+            result = if bytewise {
+                self.backend.blank_check_bytewise(address, size)
+            } else {
+                self.backend.blank_check(address, size)
+            };
+            let Err(e) = &result else {
+                return Ok(());
+            };
             rprintln!(
-                "Unable to initialize flash bank with clock {}: {}",
-                clock,
+                "Blank check error (try {}/{}): {:x?}",
+                try_number + 1,
+                BLANK_CHECK_RETRIES,
                 e
             );
+        }
+        rprintln!("Check failed after {} tries", BLANK_CHECK_RETRIES);
+        result.map_err(|status| match status.non_blank_address {
+            non_blank if (address..address + size).contains(&non_blank) => non_blank,
+            _ => address,
+        })
+    }
+
+    /// Compare the flash at `address` against `data` by reading it back, which works
+    /// without initializing the flash API.
+    fn read_back_compare(&self, address: u32, data: &[u8]) -> Result<(), f021::FlashStatus> {
+        let mut buffer = [0u8; WRITE_BLOCK_SIZE];
+        for (index, expected) in data.chunks(WRITE_BLOCK_SIZE).enumerate() {
+            let chunk_address = address + (index * WRITE_BLOCK_SIZE) as u32;
+            let actual = &mut buffer[..expected.len()];
+            self.backend.read(chunk_address, actual);
+            if let Some(offset) = actual.iter().zip(expected).position(|(a, e)| a != e) {
+                return Err(f021::FlashStatus {
+                    non_blank_address: chunk_address + offset as u32,
+                    non_blank_data: actual[offset].into(),
+                    comparison_data: expected[offset].into(),
+                    read_mode: 0,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Read the `size` bytes at `address` under both read margins, and fail if any
+    /// of them differ. TI recommends this after programming to catch cells that
+    /// would read correctly now, but not for long.
+    fn margin_verify(&self, address: u32, size: u32) -> Result<(), ErrorCode> {
+        let mut margin0 = [0u8; WRITE_BLOCK_SIZE];
+        let mut margin1 = [0u8; WRITE_BLOCK_SIZE];
+        let mut offset = 0;
+        while offset < size {
+            let length = (size - offset).min(WRITE_BLOCK_SIZE as u32) as usize;
+            for (buffer, mode) in [
+                (&mut margin0, f021::ReadMode::Margin0),
+                (&mut margin1, f021::ReadMode::Margin1),
+            ] {
+                if let Err(e) =
+                    self.backend
+                        .margin_read(address + offset, &mut buffer[..length], mode)
+                {
+                    rprintln!(
+                        "Unable to read 0x{:08x} in {:?}: {}",
+                        address + offset,
+                        mode,
+                        e
+                    );
+                    return Err(e.into());
+                }
+            }
+            if let Some(index) = (0..length).find(|&i| margin0[i] != margin1[i]) {
+                rprintln!(
+                    "Margin verify failed at 0x{:08x}: read 0x{:02x} under margin 0 and 0x{:02x} under margin 1",
+                    address + offset + index as u32,
+                    margin0[index],
+                    margin1[index]
+                );
+                return Err(AlgorithmError::MarginMismatch {
+                    offset: offset + index as u32,
+                }
+                .into());
+            }
+            offset += length as u32;
+        }
+        Ok(())
+    }
+
+    /// Select `bank_number` as the active bank.
+    fn set_active_bank(&self, bank_number: FlashBank) -> Result<(), ErrorCode> {
+        if let Err(e) = self.backend.set_active_bank(bank_number) {
+            rprintln!("Unable to set flash bank {:?}: {}", bank_number, e);
             return Err(e.into());
         }
+        Ok(())
+    }
+
+    /// Clear any errors left over from previous commands.
+    fn clear_status(&self) {
+        if let Err(e) = self.backend.clear_status() {
+            rprintln!("Unable to clear status: {}", e);
+        }
+    }
+
+    /// Program the customer OTP of `bank_number`. Each 64-bit word is only
+    /// programmed if it is still blank, since OTP can never be erased and ECC
+    /// is calculated over the whole word. Words that are all `0xff` in `data`
+    /// are left alone so they can be programmed later.
+    fn program_otp(
+        &mut self,
+        bank_number: FlashBank,
+        addr: u32,
+        data: &[u8],
+    ) -> Result<(), ErrorCode> {
+        if !self.otp_unlocked {
+            rprintln!("Refusing to program OTP at 0x{:08x} -- OTP is locked", addr);
+            return Err(AlgorithmError::OtpLocked.into());
+        }
+
+        self.set_active_bank(bank_number)?;
+
+        self.clear_status();
+
+        self.backend.set_otp_writable(bank_number, true);
+
+        let mut result = Ok(());
+        for (index, word) in data.chunks(8).enumerate() {
+            let offset = (index * 8) as u32;
+            if word.iter().all(|&b| b == 0xff) {
+                continue;
+            }
+
+            if self
+                .backend
+                .blank_check_bytewise(addr + offset, word.len() as u32)
+                .is_err()
+            {
+                let mut current = [0u8; 8];
+                self.backend.read(addr + offset, &mut current[..word.len()]);
+                if current[..word.len()] == *word {
+                    continue;
+                }
+                rprintln!(
+                    "Refusing to program OTP at 0x{:08x} -- already programmed with {:02x?}",
+                    addr + offset,
+                    &current[..word.len()]
+                );
+                result = Err(AlgorithmError::OtpProgrammed { offset }.into());
+                break;
+            }
+
+            result = self.program_chunk(
+                addr + offset,
+                offset,
+                word,
+                None,
+                f021::FlashProgrammingCommand::AutoEccGeneration,
+            );
+            if result.is_err() {
+                break;
+            }
+        }
+
+        self.backend.set_otp_writable(bank_number, false);
+        self.backend.flush();
+
+        result
+    }
+
+    /// Program `ecc` into the ECC bits of the flash starting at `data_address`,
+    /// leaving the data bits alone. This is used for images that carry their own
+    /// ECC, such as those generated by the TI linker with `--ecc`.
+    fn program_ecc(&mut self, data_address: u32, ecc: &[u8]) -> Result<(), ErrorCode> {
+        let Some(sector) = self.sector_for_address(data_address) else {
+            rprintln!(
+                "Unable to program ECC for addr {:08x} -- couldn't find sector information",
+                data_address
+            );
+            return Err(ErrorCode::new(4).unwrap());
+        };
+        // The ECC in one page can cover several sectors of data
+        self.check_protection(&sector)?;
+        let end = data_address + (ecc.len() * 8) as u32;
+        let mut next = sector.address + sector.size;
+        while next < end {
+            let Some(covered) = self.sector_for_address(next) else {
+                break;
+            };
+            self.check_protection(&covered)?;
+            next = covered.address + covered.size;
+        }
+        let bank_number = sector.bank;
+
+        self.set_active_bank(bank_number)?;
+
+        self.enable_sectors(bank_number, sector.eeprom)?;
+
+        self.clear_status();
+
+        // The ECC is programmed a row of the bank at a time, with one ECC byte for
+        // each 64-bit word of the row.
+        let width = sector.row_width / 8;
+        let lead = (data_address as usize % sector.row_width) / 8;
+
+        // The data buffer is ignored in `EccOnly` mode, but it must still describe
+        // the words that the ECC applies to.
+        let blank_data = [0xffu8; WRITE_BLOCK_SIZE];
+        for row in 0..row_count(lead, width, ecc.len()) {
+            let range = row_range(row, lead, width, ecc.len());
+            let offset = range.start as u32;
+            let bytes = &ecc[range];
+            // Erased ECC is all ones, so there's nothing to do for these.
+            if bytes.iter().all(|&b| b == 0xff) {
+                continue;
+            }
+            self.program_chunk(
+                data_address + offset * 8,
+                offset,
+                &blank_data[..bytes.len() * 8],
+                Some(bytes),
+                f021::FlashProgrammingCommand::EccOnly,
+            )?;
+        }
+
+        self.backend.flush();
+
+        Ok(())
+    }
+}
 
-        if PRINT_VERSION_INFORMATION {
-            rprint!("Getting library info:");
-            let library_info = f021::library_info();
-            rprintln!("   {:x?}", library_info);
+impl<B: FlashBackend + 'static> FlashAlgorithm for Algorithm<B> {
+    fn new(address: u32, mut clock: u32, function: Function) -> Result<Self, ErrorCode> {
+        #[cfg(not(feature = "sim"))]
+        rtt_target::rtt_init_print!(rtt_target::ChannelMode::BlockIfFull, 256);
 
-            rprint!("Getting device info: ");
-            let device_info = f021::device_info();
-            rprintln!("   {:x?}", device_info);
+        if clock == 0 {
+            clock = match clock::hclk_mhz() {
+                Some(hclk) => {
+                    rprintln!("Clock was 0 -- HCLK is running at {} MHz", hclk);
+                    hclk
+                }
+                None => {
+                    rprintln!(
+                        "Clock was 0 and HCLK is unknown -- setting to {} MHz",
+                        DEFAULT_CLOCK
+                    );
+                    DEFAULT_CLOCK
+                }
+            };
         }
 
+        let cycle_counter = f021::cycle_counter_config();
+
+        // Verifying only reads the flash, so leave the flash controller alone. Erasing
+        // and programming both need the FSM, which needs everything to be set up.
+        let backend = B::default();
+        let saved_state = if matches!(function, Function::Verify) {
+            None
+        } else {
+            rprintln!("Initializing FAPI...");
+            match backend.initialize(clock) {
+                Ok(state) => Some(state),
+                Err(e) => {
+                    rprintln!(
+                        "Unable to initialize flash bank with clock {}: {}",
+                        clock,
+                        e
+                    );
+                    return Err(e.into());
+                }
+            }
+        };
+
+        let otp_unlocked = matches!(function, Function::Program)
+            && (address == OTP_UNLOCK_KEY || take_otp_unlock_key());
+        let algorithm = Self {
+            backend,
+            clock,
+            saved_state,
+            otp_unlocked,
+            protected_sectors: protected_sectors(),
+            cycle_counter,
+        };
+
         if PRINT_SECTOR_INFORMATION {
             rprintln!("Flash bank information:");
             for bank_number in 0..8 {
                 rprint!("   Bank {}", bank_number);
-                match f021::bank_sectors(bank_number.try_into().unwrap()) {
+                match algorithm
+                    .backend
+                    .bank_sectors(bank_number.try_into().unwrap())
+                {
                     Ok(bank_sectors) => rprintln!(
                         " @ {:08x}: {:?}",
                         bank_sectors.bank_start_address,
@@ -435,10 +937,9 @@ impl FlashAlgorithm for Algorithm {
 
         if PRINT_SECTOR_INFORMATION {
             rprintln!("    sectors: [");
-            for bank_number in 0..=1 {
-                let bank_sectors = f021::bank_sectors(bank_number.try_into().unwrap()).unwrap();
+            for (bank_number, bank_sectors) in algorithm.algorithm_flash_banks() {
                 let mut start = bank_sectors.bank_start_address;
-                rprintln!("        // Bank {}", bank_number);
+                rprintln!("        // Bank {:?}", bank_number);
                 for bank_size in bank_sectors.sector_sizes() {
                     rprintln!("        {");
                     rprintln!("            size: 0x{:x},", bank_size);
@@ -462,207 +963,292 @@ impl FlashAlgorithm for Algorithm {
         //     return Err(e.into());
         // }
 
-        // rprintln!("FSM status: {:?}", f021::fsm_status());
+        if algorithm.saved_state.is_some() {
+            algorithm.resume_erase()?;
+        }
+
+        if otp_unlocked {
+            rprintln!("Customer OTP programming unlocked");
+        }
+        if algorithm.protected_sectors != [0; 8] {
+            rprintln!("Protected sectors: {:x?}", algorithm.protected_sectors);
+        }
 
         rprintln!("F021 initialized");
 
-        Ok(Self)
+        Ok(algorithm)
     }
 
     // Value at 0x4000 before: 0xe2801028
     fn erase_all(&mut self) -> Result<(), ErrorCode> {
-        // Wait for any existing FSM activity to finish
-        while f021::fsm_running() == FsmStatus::Busy {}
+        self.wait_for_fsm(FsmOperation::Other)?;
 
-        for bank_number in 0..=1 {
-            // Wait for any existing FSM activity to finish
-            while f021::fsm_running() == FsmStatus::Busy {}
+        for (bank_number, bank_sectors) in self.algorithm_flash_banks() {
+            self.wait_for_fsm(FsmOperation::Other)?;
 
-            if let Err(e) = f021::set_active_flash_bank(bank_number.try_into().unwrap()) {
-                rprintln!("Unable to set flash bank {}: {}", bank_number, e);
-                return Err(e.into());
-            }
+            self.set_active_bank(bank_number)?;
 
-            if let Err(e) = f021::enable_main_bank_sectors(u16::MAX) {
-                rprintln!(
-                    "Unable to enable main bank sectors for bank {}: {}",
-                    bank_number,
-                    e
-                );
-                return Err(e.into());
-            }
-            if let Err(e) = f021::issue_async_command_with_address(
-                f021::FlashStateCommand::EraseBank,
-                core::ptr::null_mut(),
-            ) {
-                rprintln!("Unable to erase bank {}: {}", bank_number, e);
+            self.enable_sectors(
+                bank_number,
+                bank_sectors.flash_bank_tech == FlashBankTech::FLEE,
+            )?;
+
+            self.clear_status();
+
+            // Erase one sector at a time rather than issuing a bank erase. Each step
+            // then finishes well within the sector erase time, so progress can be
+            // reported and a cancelled erase leaves at most one sector to resume.
+            // This also skips protected sectors without relying on the FSM to.
+            let sector_sizes = bank_sectors.sector_sizes();
+            let mut start = bank_sectors.bank_start_address;
+            for (index, size) in sector_sizes.iter().enumerate() {
+                if !self.sector_protected(bank_number, index) {
+                    self.erase_sector_at(start)?;
+                    rprintln!(
+                        "Bank {:?}: erased sector {}/{}",
+                        bank_number,
+                        index + 1,
+                        sector_sizes.len()
+                    );
+                }
+                start += size;
             }
-            while f021::fsm_running() == FsmStatus::Busy {}
         }
 
-        f021::flush();
+        self.backend.flush();
 
         Ok(())
     }
 
     fn erase_sector(&mut self, addr: u32) -> Result<(), ErrorCode> {
-        // Wait for any existing FSM activity to finish
-        while f021::fsm_running() == FsmStatus::Busy {}
-
-        for sector in SECTORS {
-            if addr < sector.address || addr >= sector.address + sector.size {
-                continue;
-            }
-            let bank_number = bank_for_address(addr);
+        self.wait_for_fsm(FsmOperation::Other)?;
 
+        if let Some(data_address) = ecc_data_address(addr) {
             rprintln!(
-                "Bank {:?}, Sector {{ address: 0x{:08x}, size: {} }}",
-                bank_number,
-                sector.address,
-                sector.size
+                "ECC at 0x{:08x} is erased along with the sector at 0x{:08x}",
+                addr,
+                data_address
             );
+            return Ok(());
+        }
 
-            // Wait for any existing FSM activity to finish
-            while f021::fsm_running() == FsmStatus::Busy {}
+        // probe-rs erases every sector before programming it, but the OTP can't be
+        // erased. Words that are already programmed are refused by `program_page()`.
+        if otp_bank_for_address(addr).is_some() {
+            rprintln!("OTP at 0x{:08x} can't be erased -- leaving it alone", addr);
+            return Ok(());
+        }
 
-            if let Err(e) = f021::set_active_flash_bank(bank_number) {
-                rprintln!("Unable to set flash bank {:?}: {}", bank_number, e);
-                return Err(e.into());
-            }
+        let Some(sector) = self.sector_for_address(addr) else {
+            rprintln!(
+                "Unable to erase sector addr {:08x} -- couldn't find sector information",
+                addr
+            );
+            return Err(ErrorCode::new(4).unwrap());
+        };
+        let bank_number = sector.bank;
 
-            if let Err(e) = f021::enable_main_bank_sectors(u16::MAX) {
-                rprintln!(
-                    "Unable to enable main bank sectors for bank {:?}: {}",
-                    bank_number,
-                    e
-                );
-                return Err(e.into());
-            }
+        rprintln!(
+            "Bank {:?}, Sector {} {{ address: 0x{:08x}, size: {} }}",
+            bank_number,
+            sector.index,
+            sector.address,
+            sector.size
+        );
+        self.check_protection(&sector)?;
 
-            if let Err(e) = f021::issue_async_command(f021::FlashStateCommand::ClearStatus) {
-                rprintln!("Unable to clear status: {}", e);
-            }
+        self.wait_for_fsm(FsmOperation::Other)?;
 
-            if let Err(e) = f021::issue_async_command_with_address(
-                f021::FlashStateCommand::EraseSector,
-                addr as *mut u32,
-            ) {
-                rprintln!("Unable to erase sector: {}", e);
-            }
+        self.set_active_bank(bank_number)?;
 
-            while f021::fsm_running() == FsmStatus::Busy {}
+        self.enable_sectors(bank_number, sector.eeprom)?;
 
-            f021::flush();
+        self.clear_status();
 
-            rprintln!("Sector erased");
-            return Ok(());
-        }
+        self.erase_sector_at(addr)?;
 
-        rprintln!(
-            "Unable to erase sector addr {:08x} -- couldn't find sector information",
-            addr
-        );
-        Err(ErrorCode::new(4).unwrap())
+        rprintln!("Sector erased");
+        Ok(())
     }
 
     fn program_page(&mut self, addr: u32, data: &[u8]) -> Result<(), ErrorCode> {
         rprintln!("Program Page addr: 0x{:08x} size:{}", addr, data.len());
-        // Wait for any existing FSM activity to finish
-        while f021::fsm_running() == FsmStatus::Busy {}
-
-        let bank_number = bank_for_address(addr);
+        self.wait_for_fsm(FsmOperation::Other)?;
 
-        // Wait for any existing FSM activity to finish
-        while f021::fsm_running() == FsmStatus::Busy {}
+        if let Some(bank_number) = otp_bank_for_address(addr) {
+            return self.program_otp(bank_number, addr, data);
+        }
 
-        if let Err(e) = f021::set_active_flash_bank(bank_number) {
-            rprintln!("Unable to set flash bank {:?}: {}", bank_number, e);
-            return Err(e.into());
+        if let Some(data_address) = ecc_data_address(addr) {
+            return self.program_ecc(data_address, data);
         }
 
-        if let Err(e) = f021::enable_main_bank_sectors(u16::MAX) {
+        let Some(sector) = self.sector_for_address(addr) else {
             rprintln!(
-                "Unable to enable main bank sectors for bank {:?}: {}",
-                bank_number,
-                e
+                "Unable to program addr {:08x} -- couldn't find sector information",
+                addr
             );
-            return Err(e.into());
+            return Err(ErrorCode::new(4).unwrap());
+        };
+        self.check_protection(&sector)?;
+        let bank_number = sector.bank;
+
+        self.wait_for_fsm(FsmOperation::Other)?;
+
+        self.set_active_bank(bank_number)?;
+
+        self.enable_sectors(bank_number, sector.eeprom)?;
+
+        self.clear_status();
+
+        // The page is programmed a row at a time, so each command is as large as the
+        // bank allows.
+        let width = sector.row_width;
+        let lead = addr as usize % width;
+        let rows = row_count(lead, width, data.len());
+        if rows > MAX_PAGE_ROWS {
+            rprintln!("Unable to program {} bytes at once", data.len());
+            return Err(ErrorCode::new(4).unwrap());
         }
 
-        for (offset, bytes) in data.chunks(WRITE_BLOCK_SIZE).enumerate() {
-            if let Err(e) = f021::issue_programming_command(
-                (addr + (offset * WRITE_BLOCK_SIZE) as u32) as *mut u32,
-                bytes,
-                None,
-                f021::FlashProgrammingCommand::AutoEccGeneration,
-            ) {
-                rprintln!("Unable to program sector: {}", e);
+        // The ECC of a word changes with any of its data, and flash can only go from 1
+        // to 0 without an erase, so a page that changes a word that isn't erased is
+        // refused before anything is programmed. Rows that the flash already holds,
+        // ECC included, are left alone, so that flashing an image again only programs
+        // what changed.
+        let mut changed = [0u64; MAX_PAGE_ROWS.div_ceil(64)];
+        for row in 0..rows {
+            let range = row_range(row, lead, width, data.len());
+            let start = addr + range.start as u32;
+            let end = addr + range.end as u32;
+            let mut word = start & !7;
+            while word < end {
+                let from = start.max(word);
+                let bytes = &data[(from - addr) as usize..(end.min(word + 8) - addr) as usize];
+                match self.word_state(sector.eeprom, word, from, bytes) {
+                    WordState::Programmed => {}
+                    WordState::Programmable => changed[row / 64] |= 1 << (row % 64),
+                    WordState::NeedsErase(address) => {
+                        rprintln!(
+                            "Unable to program 0x{:08x} -- the word holding it needs an erase",
+                            address
+                        );
+                        let offset = address.clamp(addr, end - 1) - addr;
+                        return Err(AlgorithmError::NeedsErase { offset }.into());
+                    }
+                }
+                word += 8;
             }
-            while f021::fsm_running() == FsmStatus::Busy {}
         }
 
-        f021::flush();
+        // A bank can't be read while it is being programmed, so there is nothing to
+        // prepare while the FSM is busy, and the changed rows are programmed in turn.
+        for row in (0..rows).filter(|row| changed[row / 64] & (1 << (row % 64)) != 0) {
+            let range = row_range(row, lead, width, data.len());
+            let offset = range.start as u32;
+            self.program_chunk(
+                addr + offset,
+                offset,
+                &data[range],
+                None,
+                data_programming_mode(),
+            )?;
+        }
+
+        self.backend.flush();
+
+        if cfg!(feature = "margin-verify") {
+            self.margin_verify(addr, data.len() as u32)?;
+        }
 
         Ok(())
     }
 
-    fn blank_check(&mut self, mut address: u32, mut size: u32, _pattern: u8) -> Result<(), ErrorCode> {
-        // Run a blank check. OR a `1` into the resulting address in case address
-        // 0 is not blank.
-        while f021::fsm_running() == FsmStatus::Busy {}
-
-        if address & (BLANK_CHECK_BYTE_COUNT - 1) != 0 {
+    fn verify(&mut self, address: u32, size: u32, data: Option<&[u8]>) -> Result<(), ErrorCode> {
+        // There is nothing to compare against, and passing would claim that the
+        // flash holds an image that nothing has checked.
+        let Some(data) = data else {
+            rprintln!("Unable to verify 0x{:08x} -- no data was given", address);
+            return Err(AlgorithmError::NoVerifyData.into());
+        };
+        let data = &data[..(size as usize).min(data.len())];
+
+        self.wait_for_fsm(FsmOperation::Other)?;
+
+        // Compare as many whole words as possible, and fall back to bytewise comparison
+        // for anything that isn't aligned. The words are in the native byte order, which
+        // is also how `Fapi_doVerify()` reads them from flash.
+        let (head, words, tail) = unsafe { data.align_to::<u32>() };
+        let result = if self.saved_state.is_none() {
+            self.read_back_compare(address, data)
+        } else if head.is_empty() && address & 3 == 0 {
+            self.backend.verify(address, words).and_then(|_| {
+                self.backend
+                    .verify_bytewise(address + (words.len() * 4) as u32, tail)
+            })
+        } else {
+            self.backend.verify_bytewise(address, data)
+        };
+
+        if let Err(status) = result {
             rprintln!(
-                "Error: Blank check must occur on an address aligned to {} bytes",
-                BLANK_CHECK_BYTE_COUNT
+                "Verify failed at 0x{:08x}: read 0x{:08x}, expected 0x{:08x}",
+                status.non_blank_address,
+                status.non_blank_data,
+                status.comparison_data
             );
-            return Err(ErrorCode::new(2).unwrap());
-        }
-
-        while size > 0 {
-            // Check in chunks of BLANK_CHECK_BYTE_COUNT bytes.
-            let to_check = size.min(BLANK_CHECK_BYTE_COUNT);
-
-            // Check multiple times. Strictly speaking, the "blank check" function is
-            // not supposed to be used on banks 0 or 1 because an erased flash contains
-            // errors, and those errors will sometimes get corrected by the machinery,
-            // resulting in blank check failures. However, performing the check multiple
-            // times appears to work.
-            //
-            // See https://e2e.ti.com/support/microcontrollers/arm-based-microcontrollers-group/arm-based-microcontrollers/f/arm-based-microcontrollers-forum/1116947/tms570lc4357-sw-stuck-in-fapi_doblankcheck-when-trying-to-update-fee-in-case-of-freertos/4139678#4139678
-            //
-            //  > Hi Sakti,
-            //  > Fapi_doBlankCheck() is to check the erase state of flash bank. As the erase
-            //  > state of the Flash is not a valid ECC condition, the ECC check and correction
-            //  > must be disabled. But the flash ECC on TMS570LC43x is enabled by default and
-            //  > can not be disabled. We don't suggest using this function in your project.
-            let mut check_passed = false;
-            for try_number in 0..BLANK_CHECK_RETRIES {
-                if let Err(e) = f021::blank_check(address, to_check) {
-                    rprintln!(
-                        "Blank check error (try {}/{}): {:x?}",
-                        try_number + 1,
-                        BLANK_CHECK_RETRIES,
-                        e
-                    );
-                    continue;
-                }
-                check_passed = true;
-                break;
+            return Err(AlgorithmError::VerifyMismatch {
+                offset: status.non_blank_address.wrapping_sub(address),
             }
-            if !check_passed {
-                rprintln!("Check failed after {} tries", BLANK_CHECK_RETRIES);
-                return Err(ErrorCode::new(6).unwrap());
+            .into());
+        }
+        Ok(())
+    }
+
+    fn blank_check(&mut self, address: u32, size: u32, pattern: u8) -> Result<(), ErrorCode> {
+        self.wait_for_fsm(FsmOperation::Other)?;
+
+        // The OTP and the ECC mirror can be checked in one pass by reading them
+        // directly, and so can main flash where ECC checking can be turned off, and any
+        // pattern other than the erased value, which the flash API can't check for.
+        let main_flash = self
+            .sector_for_address(address)
+            .is_some_and(|sector| !sector.eeprom);
+        let otp = otp_bank_for_address(address).is_some();
+        let ecc = ecc_data_address(address).is_some();
+        let by_reading = (main_flash && cfg!(feature = "ls3137")) || otp || ecc;
+        let result = if by_reading || pattern != 0xff {
+            self.blank_check_by_reading(address, size, pattern)
+        } else {
+            self.blank_check_with_api(address, size)
+        };
+        // Erased main flash needs erased ECC as well
+        let result = result.and_then(|()| match pattern {
+            0xff if main_flash => self.blank_check_ecc(address, size),
+            _ => Ok(()),
+        });
+
+        if let Err(non_blank) = result {
+            rprintln!("Blank check failed at 0x{:08x}", non_blank);
+            return Err(AlgorithmError::NotBlank {
+                offset: non_blank.wrapping_sub(address),
             }
-            address += to_check;
-            size -= to_check;
+            .into());
         }
         Ok(())
     }
 }
 
-impl Drop for Algorithm {
+impl<B: FlashBackend> Drop for Algorithm<B> {
     fn drop(&mut self) {
-        // TODO: Add code here to uninitialize the flash algorithm.
+        // Put the flash controller back the way the application had it, so that the
+        // application can be resumed without a reset.
+        if let Some(state) = self.saved_state.take() {
+            // A stuck FSM has already been cleared by the time this gives up on it
+            let _ = self.wait_for_fsm(FsmOperation::Other);
+            self.backend.flush();
+            self.backend.restore(state);
+        }
+        f021::restore_cycle_counter(self.cycle_counter);
     }
 }
@@ -0,0 +1,68 @@
+//! Routines exported alongside the ones that probe-rs calls, for host tools that
+//! drive the algorithm directly. Each one is called like the standard entry points,
+//! by setting PC to its symbol between `Init()` and `UnInit()`, and returns its
+//! result in r0.
+
+use crate::AlgorithmError;
+use crate::backend::{F021, FlashBackend};
+use crate::checksum::{self, ChecksumKind};
+use flash_algorithm::ErrorCode;
+
+/// Suspend the erase in progress, so that a long erase can be cancelled without
+/// waiting for it or resetting the device. The core may be halted in the middle of
+/// `EraseSector()` or `EraseChip()` to call this. FMSTAT reports ESUSP once the FSM
+/// has stopped, and the erase is resumed by `EraseResume()` or by the next `Init()`
+/// that erases or programs.
+#[unsafe(no_mangle)]
+pub extern "C" fn EraseSuspend() -> u32 {
+    F021.suspend_erase();
+    0
+}
+
+/// Resume an erase suspended by `EraseSuspend()`. This returns as soon as the
+/// erase has restarted.
+#[unsafe(no_mangle)]
+pub extern "C" fn EraseResume() -> u32 {
+    match F021.resume_erase() {
+        Ok(()) => 0,
+        Err(e) => ErrorCode::from(e).get(),
+    }
+}
+
+/// Calculate a checksum of `size` bytes of flash at `address`, so that a host tool
+/// can compare it against the image instead of reading the flash back. `kind` is a
+/// `ChecksumKind`: 0 for CRC-32, or 1 for the PSA signature, which only covers whole
+/// words and ignores any trailing bytes.
+///
+/// The checksum is returned in r0 in place of an error code. Any other `kind` is
+/// refused with `0x130000KK`, where `KK` is its low byte, rather than given a
+/// checksum that the host didn't ask for. A range that isn't entirely in main
+/// flash, the EEPROM emulation bank or the ECC mirror isn't read, and gives 0 like
+/// an empty range does.
+#[unsafe(no_mangle)]
+pub extern "C" fn Checksum(address: u32, size: u32, kind: u32) -> u32 {
+    let kind = match ChecksumKind::try_from(kind) {
+        Ok(kind) => kind,
+        Err(kind) => return ErrorCode::from(AlgorithmError::UnknownChecksumKind { kind }).get(),
+    };
+    if !checksum::in_flash(address, size) {
+        return 0;
+    }
+    match kind {
+        ChecksumKind::Crc32 => checksum::crc32(&F021, address, size),
+        ChecksumKind::Psa => F021.psa(address, size / 4),
+    }
+}
+
+/// Check the application image in the same way as the bootloader, so that the
+/// host can confirm that it will boot. This reads the application header at the
+/// address in r0, or at `APP_HEADER_ADDRESS` if r0 is 0, and compares the
+/// Fletcher-32 of the range that it describes with the checksum in the header.
+/// Returns 0 if they match.
+#[unsafe(no_mangle)]
+pub extern "C" fn ValidateImage(header_address: u32) -> u32 {
+    match checksum::validate_image(&F021, header_address) {
+        Ok(()) => 0,
+        Err(e) => e.get(),
+    }
+}
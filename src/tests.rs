@@ -0,0 +1,750 @@
+//! Tests of the algorithm against the simulated F021 API. Run these with `cargo test-sim`.
+
+use super::*;
+use f021::sim;
+
+/// Bank 1 sector 0, which is 128 KB on every supported device
+const BANK1_SECTOR: u32 = if cfg!(feature = "ls3137") {
+    0x0018_0000
+} else {
+    0x0020_0000
+};
+
+/// The start of the EEPROM emulation bank
+const EEPROM_SECTOR: u32 = 0xf020_0000;
+
+/// The page size of the EEPROM emulation bank's `algorithm!()` definition
+const EEPROM_PAGE_SIZE: usize = if cfg!(feature = "ls3137") {
+    16 * 1024
+} else {
+    4 * 1024
+};
+
+/// Bank 0 sectors 1 and 2
+const BANK0_SECTOR1: u32 = if cfg!(feature = "ls3137") {
+    0x8000
+} else {
+    0x4000
+};
+const BANK0_SECTOR2: u32 = 2 * BANK0_SECTOR1;
+
+fn algorithm(function: Function) -> Algorithm {
+    sim::reset();
+    Algorithm::new(0, DEFAULT_CLOCK, function).unwrap()
+}
+
+/// Start the algorithm with sectors 0 and 1 of bank 0 protected through the
+/// override in RAM, without resetting the simulated flash.
+fn protected_algorithm(function: Function) -> Algorithm {
+    let magic = PROTECTION_OVERRIDE_ADDRESS as *mut u32;
+    f021::write_register(magic, PROTECTION_OVERRIDE_MAGIC);
+    f021::write_register(magic.wrapping_add(1), 0b11);
+    Algorithm::new(0, DEFAULT_CLOCK, function).unwrap()
+}
+
+fn read(address: u32, size: usize) -> Vec<u8> {
+    let mut buffer = vec![0u8; size];
+    f021::read(address, &mut buffer);
+    buffer
+}
+
+fn pattern(size: usize) -> Vec<u8> {
+    (0..size).map(|i| (i * 7 + 3) as u8).collect()
+}
+
+#[test]
+fn program_and_verify() {
+    let mut algorithm = algorithm(Function::Program);
+    let data = pattern(1024);
+
+    algorithm.erase_sector(BANK1_SECTOR).unwrap();
+    algorithm.blank_check(BANK1_SECTOR, 1024, 0xff).unwrap();
+    algorithm.program_page(BANK1_SECTOR, &data).unwrap();
+
+    assert_eq!(read(BANK1_SECTOR, data.len()), data);
+    algorithm
+        .verify(BANK1_SECTOR, data.len() as u32, Some(&data))
+        .unwrap();
+    assert!(algorithm.blank_check(BANK1_SECTOR, 1024, 0xff).is_err());
+}
+
+#[test]
+fn eeprom_sectors_are_found_past_the_reported_banks() {
+    let algorithm = algorithm(Function::Erase);
+    assert!(f021::device_info().number_of_banks < 7);
+    let sector = algorithm.sector_for_address(EEPROM_SECTOR + 4).unwrap();
+    assert_eq!(sector.bank as u32, 7);
+    assert!(sector.eeprom);
+    assert_eq!(sector.address, EEPROM_SECTOR);
+}
+
+#[test]
+fn verify_without_data_fails() {
+    let mut algorithm = algorithm(Function::Verify);
+    assert_eq!(
+        algorithm.verify(BANK1_SECTOR, 64, None),
+        Err(ErrorCode::new(0x1200_0000).unwrap())
+    );
+}
+
+#[test]
+fn verify_reports_mismatch_offset() {
+    let mut algorithm = algorithm(Function::Program);
+    let mut data = pattern(64);
+
+    algorithm.program_page(BANK1_SECTOR, &data).unwrap();
+    data[41] ^= 0x10;
+
+    // Category 0x08, offset 40: the word containing the mismatched byte
+    assert_eq!(
+        algorithm.verify(BANK1_SECTOR, data.len() as u32, Some(&data)),
+        Err(ErrorCode::new(0x0800_2800).unwrap())
+    );
+}
+
+#[test]
+fn verify_reports_offsets_past_64_kb() {
+    let mut algorithm = algorithm(Function::Program);
+    let data = pattern(64);
+    algorithm
+        .program_page(BANK1_SECTOR + 0x1_2340, &data)
+        .unwrap();
+
+    // Offset 0x12340, with bits 16-23 in the reason byte
+    let expected = [0xff; 0x1_2380];
+    assert_eq!(
+        algorithm.verify(BANK1_SECTOR, expected.len() as u32, Some(&expected)),
+        Err(ErrorCode::new(0x0823_4001).unwrap())
+    );
+    assert_eq!(
+        algorithm.blank_check(BANK1_SECTOR, 0x2_0000, 0xff),
+        Err(ErrorCode::new(0x0d23_4001).unwrap())
+    );
+}
+
+#[test]
+fn programming_a_one_over_a_zero_fails() {
+    let mut algorithm = algorithm(Function::Program);
+
+    algorithm.program_page(BANK1_SECTOR, &[0x00; 32]).unwrap();
+
+    // Category 0x11 at offset 0, and the flash is left alone
+    assert_eq!(
+        algorithm.program_page(BANK1_SECTOR, &[0x5a; 32]),
+        Err(ErrorCode::new(0x1100_0000).unwrap())
+    );
+    assert_eq!(read(BANK1_SECTOR, 32), [0x00; 32]);
+
+    algorithm.erase_sector(BANK1_SECTOR).unwrap();
+    algorithm.program_page(BANK1_SECTOR, &[0x5a; 32]).unwrap();
+    assert_eq!(read(BANK1_SECTOR, 32), [0x5a; 32]);
+}
+
+/// `true` if this build exports the main flash banks rather than another region.
+const EXPORTS_MAIN_FLASH: bool = !cfg!(any(feature = "eeprom", feature = "otp", feature = "ecc"));
+
+/// What `size` bytes of `pattern()` read as after a chip erase.
+fn after_erase_all(erased: bool, size: usize) -> Vec<u8> {
+    if erased {
+        vec![0xff; size]
+    } else {
+        pattern(size)
+    }
+}
+
+#[test]
+fn erase_all_erases_the_exported_region() {
+    let mut algorithm = algorithm(Function::Erase);
+
+    algorithm.program_page(0, &pattern(256)).unwrap();
+    algorithm.program_page(BANK1_SECTOR, &pattern(256)).unwrap();
+    algorithm.program_page(EEPROM_SECTOR, &pattern(8)).unwrap();
+    algorithm.erase_all().unwrap();
+
+    // Each region is covered by its own build of the algorithm
+    assert_eq!(read(0, 256), after_erase_all(EXPORTS_MAIN_FLASH, 256));
+    assert_eq!(
+        read(BANK1_SECTOR, 256),
+        after_erase_all(EXPORTS_MAIN_FLASH, 256)
+    );
+    assert_eq!(
+        read(EEPROM_SECTOR, 8),
+        after_erase_all(cfg!(feature = "eeprom"), 8)
+    );
+}
+
+#[test]
+fn program_eeprom_bank() {
+    let mut algorithm = algorithm(Function::Program);
+
+    // A whole EEPROM page, which takes many of the bank's 8-byte rows
+    let data = pattern(EEPROM_PAGE_SIZE);
+    algorithm.program_page(EEPROM_SECTOR, &data).unwrap();
+    assert_eq!(read(EEPROM_SECTOR, data.len()), data);
+    algorithm
+        .verify(EEPROM_SECTOR, data.len() as u32, Some(&data))
+        .unwrap();
+
+    algorithm.erase_sector(EEPROM_SECTOR).unwrap();
+    assert_eq!(read(EEPROM_SECTOR, data.len()), vec![0xff; data.len()]);
+}
+
+#[test]
+fn otp_requires_unlock() {
+    let mut algorithm = algorithm(Function::Program);
+    assert_eq!(
+        algorithm.program_page(device::OTP_ADDRESS, &pattern(8)),
+        Err(ErrorCode::new(0x0900_0000).unwrap())
+    );
+    assert_eq!(read(device::OTP_ADDRESS, 8), [0xff; 8]);
+
+    let mut algorithm: Algorithm =
+        Algorithm::new(OTP_UNLOCK_KEY, DEFAULT_CLOCK, Function::Program).unwrap();
+    algorithm
+        .program_page(device::OTP_ADDRESS, &pattern(8))
+        .unwrap();
+    assert_eq!(read(device::OTP_ADDRESS, 8), pattern(8));
+
+    // Programming the same value again is fine, but a different one is refused
+    algorithm
+        .program_page(device::OTP_ADDRESS, &pattern(8))
+        .unwrap();
+    assert_eq!(
+        algorithm.program_page(device::OTP_ADDRESS, &[0; 8]),
+        Err(ErrorCode::new(0x0a00_0000).unwrap())
+    );
+}
+
+#[test]
+fn otp_can_be_unlocked_through_ram() {
+    sim::reset();
+    f021::write_register(OTP_UNLOCK_ADDRESS as *mut u32, OTP_UNLOCK_KEY);
+
+    // Erasing doesn't consume the key, and leaves the OTP alone
+    let mut algorithm: Algorithm =
+        Algorithm::new(device::OTP_ADDRESS, DEFAULT_CLOCK, Function::Erase).unwrap();
+    algorithm.erase_sector(device::OTP_ADDRESS).unwrap();
+    drop(algorithm);
+
+    let mut algorithm: Algorithm =
+        Algorithm::new(device::OTP_ADDRESS, DEFAULT_CLOCK, Function::Program).unwrap();
+    algorithm
+        .program_page(device::OTP_ADDRESS, &pattern(8))
+        .unwrap();
+    algorithm
+        .blank_check(device::OTP_ADDRESS + 8, 1024, 0xff)
+        .unwrap();
+    drop(algorithm);
+    assert_eq!(read(device::OTP_ADDRESS, 8), pattern(8));
+
+    // The key only unlocks one session
+    let mut algorithm: Algorithm =
+        Algorithm::new(device::OTP_ADDRESS, DEFAULT_CLOCK, Function::Program).unwrap();
+    assert_eq!(
+        algorithm.program_page(device::OTP_ADDRESS + 8, &pattern(8)),
+        Err(ErrorCode::new(0x0900_0000).unwrap())
+    );
+}
+
+#[test]
+fn protected_sectors_are_refused() {
+    sim::reset();
+    let mut algorithm = protected_algorithm(Function::Program);
+
+    // Category 0x0b, bank 0, sector 1
+    let protected = Err(ErrorCode::new(0x0b00_0100).unwrap());
+    assert_eq!(algorithm.erase_sector(BANK0_SECTOR1), protected);
+    assert_eq!(
+        algorithm.program_page(BANK0_SECTOR1, &pattern(32)),
+        protected
+    );
+    assert_eq!(read(BANK0_SECTOR1, 32), [0xff; 32]);
+
+    algorithm.program_page(BANK0_SECTOR2, &pattern(32)).unwrap();
+    assert_eq!(read(BANK0_SECTOR2, 32), pattern(32));
+}
+
+#[test]
+#[cfg(not(any(feature = "eeprom", feature = "otp", feature = "ecc")))]
+fn erase_all_skips_protected_sectors() {
+    let mut algorithm = algorithm(Function::Program);
+    algorithm.program_page(0, &pattern(256)).unwrap();
+    algorithm
+        .program_page(BANK0_SECTOR2, &pattern(256))
+        .unwrap();
+    algorithm.program_page(BANK1_SECTOR, &pattern(256)).unwrap();
+
+    let mut algorithm = protected_algorithm(Function::Erase);
+    algorithm.erase_all().unwrap();
+
+    assert_eq!(read(0, 256), pattern(256));
+    assert_eq!(read(BANK0_SECTOR2, 256), [0xff; 256]);
+    assert_eq!(read(BANK1_SECTOR, 256), [0xff; 256]);
+}
+
+#[test]
+fn verifying_leaves_the_controller_alone() {
+    let data = pattern(64);
+    let mut algorithm = algorithm(Function::Program);
+    algorithm.program_page(BANK1_SECTOR, &data).unwrap();
+    drop(algorithm);
+
+    f021::write_register(device::FBPWRMODE, 0x5);
+    f021::write_register(device::FRDCNTL, 0x200);
+    let mut algorithm: Algorithm = Algorithm::new(0, DEFAULT_CLOCK, Function::Verify).unwrap();
+    algorithm
+        .verify(BANK1_SECTOR, data.len() as u32, Some(&data))
+        .unwrap();
+    assert_eq!(
+        algorithm.verify(BANK1_SECTOR, 4, Some(&[0; 4])),
+        Err(ErrorCode::new(0x0800_0000).unwrap())
+    );
+    drop(algorithm);
+
+    assert_eq!(f021::read_register(device::FBPWRMODE), 0x5);
+    assert_eq!(f021::read_register(device::FRDCNTL), 0x200);
+}
+
+#[test]
+fn dropping_restores_the_controller() {
+    sim::reset();
+    f021::write_register(device::FBPWRMODE, 0x5);
+    f021::write_register(device::FRDCNTL, 0x200);
+    f021::write_register(device::FMAC, 0);
+
+    let mut algorithm: Algorithm = Algorithm::new(0, DEFAULT_CLOCK, Function::Program).unwrap();
+    algorithm.program_page(BANK1_SECTOR, &pattern(32)).unwrap();
+    assert_ne!(f021::read_register(device::FBPWRMODE), 0x5);
+    assert_eq!(f021::read_register(device::FMAC) & 7, 1);
+    drop(algorithm);
+
+    assert_eq!(f021::read_register(device::FBPWRMODE), 0x5);
+    assert_eq!(f021::read_register(device::FRDCNTL), 0x200);
+    assert_eq!(f021::read_register(device::FMAC), 0);
+}
+
+#[test]
+fn dropping_restores_the_cycle_counter() {
+    sim::reset();
+    // The counters stopped, with the divider on
+    let application = f021::CycleCounterConfig {
+        pmcr: 1 << 3,
+        pmcntenset: 0,
+    };
+    f021::restore_cycle_counter(application);
+
+    for function in [Function::Program, Function::Verify] {
+        let mut algorithm: Algorithm = Algorithm::new(0, DEFAULT_CLOCK, function).unwrap();
+        algorithm.blank_check(EEPROM_SECTOR, 64, 0xff).unwrap();
+        // PMCR.E and PMCNTENSET.C set, and PMCR.D clear, while deadlines run
+        let running = f021::cycle_counter_config();
+        assert_eq!((running.pmcr, running.pmcntenset), (1, 1 << 31));
+        drop(algorithm);
+        assert_eq!(f021::cycle_counter_config(), application);
+    }
+}
+
+#[test]
+fn a_failed_init_restores_the_controller() {
+    sim::reset();
+    f021::write_register(device::FBPWRMODE, 0x5);
+    f021::write_register(device::FRDCNTL, 0x200);
+    f021::write_register(device::EWAIT, 0x0002_0000);
+
+    // Even 15 EWAIT wait states aren't enough for 300 MHz
+    assert!(Algorithm::<F021>::new(0, 300, Function::Program).is_err());
+    assert_eq!(f021::read_register(device::FBPWRMODE), 0x5);
+    assert_eq!(f021::read_register(device::FRDCNTL), 0x200);
+    assert_eq!(f021::read_register(device::EWAIT), 0x0002_0000);
+}
+
+#[test]
+fn hclk_is_worked_out_from_the_pll() {
+    sim::reset();
+    assert_eq!(clock::hclk_mhz(), Some(16));
+
+    // PLL1 at 16 MHz / 8 * 150 / 2 = 150 MHz
+    f021::write_register(device::PLLCTL1, (1 << 24) | (7 << 16) | (149 << 8));
+    f021::write_register(device::GHVSRC, 1);
+    let algorithm: Algorithm = Algorithm::new(0, 0, Function::Erase).unwrap();
+    assert_eq!(sim::hclk(), 150);
+    assert_eq!((f021::read_register(device::FRDCNTL) >> 8) & 15, 3);
+
+    // An unknown clock falls back to the default
+    drop(algorithm);
+    f021::write_register(device::GHVSRC, 3);
+    assert_eq!(clock::hclk_mhz(), None);
+    let _algorithm: Algorithm = Algorithm::new(0, 0, Function::Erase).unwrap();
+    assert_eq!(sim::hclk(), DEFAULT_CLOCK);
+}
+
+#[test]
+fn wait_states_follow_hclk() {
+    sim::reset();
+    let limits = &device::WAIT_STATE_LIMITS;
+    assert_eq!(
+        wait_states::WaitStates::for_hclk(45, limits),
+        wait_states::WaitStates { rwait: 0, ewait: 2 }
+    );
+
+    // Overclocked beyond the rated HCLK still gets enough wait states to initialize
+    let _algorithm: Algorithm = Algorithm::new(0, 200, Function::Program).unwrap();
+    assert_eq!(sim::hclk(), 200);
+    assert_eq!((f021::read_register(device::FRDCNTL) >> 8) & 15, 4);
+    assert_eq!((f021::read_register(device::EWAIT) >> 16) & 15, 13);
+}
+
+#[test]
+fn a_stuck_fsm_times_out() {
+    let mut algorithm = algorithm(Function::Program);
+    sim::hang();
+
+    // Category 0x0c with BUSY and PGM in FMSTAT, while programming
+    assert_eq!(
+        algorithm.program_page(BANK1_SECTOR, &pattern(32)),
+        Err(ErrorCode::new(0x0c01_4001).unwrap())
+    );
+    // Anything else waits for the stuck program, which has to give up as well
+    assert_eq!(
+        algorithm.erase_sector(BANK1_SECTOR),
+        Err(ErrorCode::new(0x0c01_4000).unwrap())
+    );
+}
+
+#[test]
+fn suspended_erase_is_resumed_by_the_next_session() {
+    let algorithm = algorithm(Function::Erase);
+
+    // Cancel an erase part of the way through, as a host tool would
+    algorithm.set_active_bank(FlashBank::_1).unwrap();
+    algorithm.enable_sectors(FlashBank::_1, false).unwrap();
+    algorithm.backend.erase_sector(BANK1_SECTOR).unwrap();
+    assert_eq!(routines::EraseSuspend(), 0);
+    assert!(f021::fsm_status().esusp());
+    assert!(!f021::fsm_status().busy());
+    // Only SUSPEND_NOW is written, so FSMEXECUTE keeps its reset value
+    assert_eq!(f021::read_register(device::FSM_EXECUTE), 0x0005_000a);
+    drop(algorithm);
+
+    let _algorithm: Algorithm = Algorithm::new(0, DEFAULT_CLOCK, Function::Program).unwrap();
+    assert!(!f021::fsm_status().esusp());
+    assert!(!f021::fsm_status().busy());
+}
+
+#[test]
+fn blank_check_reports_the_first_programmed_byte() {
+    let mut algorithm = algorithm(Function::Program);
+    algorithm.erase_sector(BANK1_SECTOR).unwrap();
+    algorithm.blank_check(BANK1_SECTOR, 4096, 0xff).unwrap();
+
+    // Category 0x0d, offset 0x41: the first byte that isn't 0xff. Main flash on the
+    // TMS570LC43x goes through the flash API, which reports the word instead.
+    let mut data = [0xff; 32];
+    data[1] = 0x7f;
+    algorithm.program_page(BANK1_SECTOR + 0x40, &data).unwrap();
+    let offset = if cfg!(feature = "ls3137") { 0x41 } else { 0x40 };
+    assert_eq!(
+        algorithm.blank_check(BANK1_SECTOR, 4096, 0xff),
+        Err(ErrorCode::new(0x0d00_0000 | offset << 8).unwrap())
+    );
+
+    // ECC that isn't blank is reported at the start of its 64-bit word
+    algorithm.erase_sector(BANK1_SECTOR).unwrap();
+    algorithm
+        .backend
+        .program(
+            BANK1_SECTOR + 0x88,
+            &[0xff; 8],
+            Some(&[0x12]),
+            f021::FlashProgrammingCommand::EccOnly,
+        )
+        .unwrap();
+    algorithm.wait_for_fsm(FsmOperation::Program).unwrap();
+    assert_eq!(
+        algorithm.blank_check(BANK1_SECTOR, 4096, 0xff),
+        Err(ErrorCode::new(0x0d00_8800).unwrap())
+    );
+}
+
+#[test]
+fn blank_check_unaligned_ranges_and_patterns() {
+    let mut algorithm = algorithm(Function::Program);
+    algorithm.blank_check(EEPROM_SECTOR + 3, 29, 0xff).unwrap();
+    algorithm.blank_check(BANK1_SECTOR + 5, 27, 0xff).unwrap();
+
+    // The EEPROM bank uses the flash API, which reports the word that isn't blank
+    algorithm
+        .program_page(EEPROM_SECTOR + 8, &pattern(8))
+        .unwrap();
+    algorithm.blank_check(EEPROM_SECTOR + 1, 7, 0xff).unwrap();
+    algorithm.blank_check(EEPROM_SECTOR + 17, 13, 0xff).unwrap();
+    assert_eq!(
+        algorithm.blank_check(EEPROM_SECTOR + 1, 30, 0xff),
+        Err(ErrorCode::new(0x0d00_0700).unwrap())
+    );
+
+    algorithm.program_page(BANK1_SECTOR, &[0; 32]).unwrap();
+    algorithm.blank_check(BANK1_SECTOR + 5, 27, 0x00).unwrap();
+    assert_eq!(
+        algorithm.blank_check(BANK1_SECTOR + 5, 27, 0xff),
+        Err(ErrorCode::new(0x0d00_0000).unwrap())
+    );
+}
+
+#[test]
+fn blank_check_outside_of_flash_fails_at_the_start() {
+    // The flash API refuses the address without reporting one of its own
+    let mut algorithm = algorithm(Function::Program);
+    assert_eq!(
+        algorithm.blank_check(0x0800_0010, 64, 0xff),
+        Err(ErrorCode::new(0x0d00_0000).unwrap())
+    );
+}
+
+#[test]
+fn margin_verify_finds_weakly_programmed_bits() {
+    let mut algorithm = algorithm(Function::Program);
+    algorithm.program_page(BANK1_SECTOR, &pattern(64)).unwrap();
+    algorithm.margin_verify(BANK1_SECTOR, 64).unwrap();
+
+    // Category 0x0e, offset 0x25
+    sim::weaken(BANK1_SECTOR + 0x25, 0x01);
+    assert_eq!(
+        algorithm.margin_verify(BANK1_SECTOR, 64),
+        Err(ErrorCode::new(0x0e00_2500).unwrap())
+    );
+}
+
+#[test]
+fn checksum_matches_the_programmed_image() {
+    let mut algorithm = algorithm(Function::Program);
+    algorithm.program_page(BANK1_SECTOR, b"123456789").unwrap();
+    drop(algorithm);
+
+    // The standard CRC-32 check value
+    assert_eq!(routines::Checksum(BANK1_SECTOR, 9, 0), 0xcbf4_3926);
+    // Unknown kinds are refused rather than given a CRC-32
+    assert_eq!(routines::Checksum(BANK1_SECTOR, 9, 7), 0x1300_0007);
+    assert_eq!(routines::Checksum(0x0800_0000, 9, 0x102), 0x1300_0002);
+
+    let psa = routines::Checksum(BANK1_SECTOR, 8, 1);
+    assert_eq!(routines::Checksum(BANK1_SECTOR, 9, 1), psa);
+    assert!(f021::psa_verify(BANK1_SECTOR, 2, psa).is_ok());
+    assert!(f021::psa_verify(BANK1_SECTOR, 2, psa ^ 1).is_err());
+    assert_ne!(routines::Checksum(BANK1_SECTOR + 4, 8, 1), psa);
+
+    // Ranges that leave flash aren't read
+    assert_ne!(routines::Checksum(device::MAIN_FLASH_SIZE - 8, 8, 0), 0);
+    assert_ne!(
+        routines::Checksum(device::EEPROM_ADDRESS + device::EEPROM_SIZE - 8, 8, 0),
+        0
+    );
+    assert_eq!(routines::Checksum(device::MAIN_FLASH_SIZE - 4, 8, 0), 0);
+    assert_eq!(routines::Checksum(0x0800_0000, 8, 1), 0);
+    assert_eq!(routines::Checksum(0xffff_fff8, 16, 0), 0);
+}
+
+fn app_header(start: u32, length: u32, checksum: u32) -> Vec<u8> {
+    [APP_HEADER_MAGIC, start, length, checksum]
+        .iter()
+        .flat_map(|word| word.to_ne_bytes())
+        .collect()
+}
+
+#[test]
+fn validate_image_checks_the_header_checksum() {
+    let image = BANK1_SECTOR + 0x100;
+    let mut algorithm = algorithm(Function::Program);
+    algorithm.program_page(image, b"abcdefgh").unwrap();
+    algorithm
+        .program_page(BANK1_SECTOR, &app_header(image, 8, 0xebe1_9591))
+        .unwrap();
+    algorithm
+        .program_page(BANK1_SECTOR + 0x40, &app_header(image, 5, 0xf04f_c729))
+        .unwrap();
+    algorithm
+        .program_page(BANK1_SECTOR + 0x80, &app_header(image, 6, 0x1234_5678))
+        .unwrap();
+    drop(algorithm);
+
+    // Through the F021 API, and in software for an odd length
+    assert_eq!(routines::ValidateImage(BANK1_SECTOR), 0);
+    assert_eq!(routines::ValidateImage(BANK1_SECTOR + 0x40), 0);
+    assert_eq!(
+        checksum::fletcher32(&F021, image, 8),
+        F021.fletcher32(image, 8)
+    );
+
+    assert_eq!(routines::ValidateImage(BANK1_SECTOR + 0x80), 0x1000_0000);
+    assert_eq!(routines::ValidateImage(BANK1_SECTOR + 0xc0), 0x0f00_0000);
+}
+
+#[test]
+fn validate_image_rejects_ranges_outside_of_flash() {
+    let mut algorithm = algorithm(Function::Program);
+    let end = device::MAIN_FLASH_SIZE;
+    algorithm
+        .program_page(BANK1_SECTOR, &app_header(end - 4, 8, 0))
+        .unwrap();
+    algorithm
+        .program_page(BANK1_SECTOR + 0x40, &app_header(0x0800_0000, 8, 0))
+        .unwrap();
+    algorithm
+        .program_page(BANK1_SECTOR + 0x80, &app_header(0x100, u32::MAX, 0))
+        .unwrap();
+    drop(algorithm);
+
+    for header in [BANK1_SECTOR, BANK1_SECTOR + 0x40, BANK1_SECTOR + 0x80] {
+        assert_eq!(routines::ValidateImage(header), 0x0f00_0000);
+    }
+    // The header itself has to be in flash too
+    assert_eq!(routines::ValidateImage(end - 8), 0x0f00_0000);
+    assert_eq!(routines::ValidateImage(0x0800_0000), 0x0f00_0000);
+}
+
+#[test]
+#[cfg(not(feature = "image-ecc"))]
+fn programming_fails_if_the_ecc_needs_a_bit_set() {
+    let mut algorithm = algorithm(Function::Program);
+    let word = BANK1_SECTOR + 0x40;
+    let first = [0xf0; 8];
+    algorithm.program_page(word, &first).unwrap();
+
+    // Clearing one more data bit changes the ECC, and for some bit that means
+    // setting an ECC bit that is already programmed
+    let second = (0..64)
+        .map(|bit| {
+            let mut data = first;
+            data[bit / 8] &= !(1 << (bit % 8));
+            data
+        })
+        .find(|data| sim::ecc_for(word, data) & !sim::ecc_for(word, &first) != 0)
+        .unwrap();
+    algorithm
+        .backend
+        .program(
+            word,
+            &second,
+            None,
+            f021::FlashProgrammingCommand::AutoEccGeneration,
+        )
+        .unwrap();
+    algorithm.wait_for_fsm(FsmOperation::Program).unwrap();
+    assert!(f021::fsm_status().invdat());
+    assert_eq!(read(word, 8), first);
+}
+
+#[test]
+fn unchanged_chunks_are_not_programmed() {
+    let mut algorithm = algorithm(Function::Program);
+    let data = pattern(96);
+    algorithm.program_page(BANK1_SECTOR, &data).unwrap();
+
+    // Nothing needs programming, so a wedged FSM goes unnoticed
+    sim::hang();
+    algorithm.program_page(BANK1_SECTOR, &data).unwrap();
+}
+
+#[test]
+fn programming_reports_the_first_byte_that_needs_an_erase() {
+    let mut algorithm = algorithm(Function::Program);
+    algorithm.program_page(BANK1_SECTOR, &[0x0f; 64]).unwrap();
+
+    // The end of the page is erased and the start is unchanged, but the byte at
+    // offset 0x24 changes a word that is already programmed, so none of it is
+    // programmed
+    let mut data = [0x0f; 96];
+    data[64..].fill(0x07);
+    data[0x24] = 0x07;
+    assert_eq!(
+        algorithm.program_page(BANK1_SECTOR, &data),
+        Err(ErrorCode::new(0x1100_2400).unwrap())
+    );
+    assert_eq!(read(BANK1_SECTOR, 64), [0x0f; 64]);
+    assert_eq!(read(BANK1_SECTOR + 64, 32), [0xff; 32]);
+}
+
+#[test]
+#[cfg(not(feature = "image-ecc"))]
+fn clearing_more_bits_of_a_programmed_word_needs_an_erase() {
+    let mut algorithm = algorithm(Function::Program);
+    let word = BANK1_SECTOR + 0x40;
+    algorithm.program_page(word, &[0xf0; 8]).unwrap();
+
+    // Only data bits go from 1 to 0, but the ECC of the word would have to change
+    // as well
+    assert_eq!(
+        algorithm.program_page(word, &[0x70, 0xf0, 0xf0, 0xf0, 0xf0, 0xf0, 0xf0, 0xf0]),
+        Err(ErrorCode::new(0x1100_0000).unwrap())
+    );
+    assert_eq!(read(word, 8), [0xf0; 8]);
+    let mut ecc = [0u8];
+    f021::read(device::ECC_ADDRESS + word / 8, &mut ecc);
+    assert_eq!(ecc[0], sim::ecc_for(word, &[0xf0; 8]));
+}
+
+#[test]
+#[cfg(not(feature = "image-ecc"))]
+fn erased_rows_get_their_ecc_programmed() {
+    let mut algorithm = algorithm(Function::Program);
+    let mut data = [0xff; 64];
+    data[32..].copy_from_slice(&pattern(32));
+    algorithm.program_page(BANK1_SECTOR, &data).unwrap();
+
+    // The rows of 0xff still need ECC, which on the TMS570LC43x isn't 0xff
+    let ecc: Vec<u8> = data
+        .chunks(8)
+        .enumerate()
+        .map(|(index, word)| {
+            sim::ecc_for(BANK1_SECTOR + index as u32 * 8, word.try_into().unwrap())
+        })
+        .collect();
+    assert_eq!(read(device::ECC_ADDRESS + BANK1_SECTOR / 8, 8), ecc);
+}
+
+#[test]
+fn unaligned_pages_are_programmed_a_row_at_a_time() {
+    let mut algorithm = algorithm(Function::Program);
+    let data = pattern(200);
+
+    // Rows are checked by the sim, so a chunk that crosses one would be refused
+    algorithm.program_page(BANK1_SECTOR + 5, &data).unwrap();
+    algorithm
+        .program_page(EEPROM_SECTOR + 3, &data[..21])
+        .unwrap();
+    assert_eq!(read(BANK1_SECTOR + 5, 200), data);
+    assert_eq!(read(EEPROM_SECTOR + 3, 21), data[..21]);
+}
+
+#[test]
+fn rows_follow_the_bank_width() {
+    let mut algorithm = algorithm(Function::Program);
+
+    // Narrow the main banks to one 64-bit word, 72 bits with ECC. The sim refuses
+    // any programming command that is wider than that.
+    let fcfg_bank = f021::read_register(device::FCFG_BANK);
+    f021::write_register(device::FCFG_BANK, (fcfg_bank & !(0xfff << 4)) | (72 << 4));
+    let data = pattern(100);
+    algorithm.program_page(BANK1_SECTOR + 5, &data).unwrap();
+    assert_eq!(read(BANK1_SECTOR + 5, 100), data);
+}
+
+#[test]
+fn ecc_mirror_is_programmed_from_the_image() {
+    let mut algorithm = algorithm(Function::Program);
+    let ecc: Vec<u8> = (0..8).map(|i| 0x10 + i).collect();
+    let ecc_address = device::ECC_ADDRESS + BANK1_SECTOR / 8;
+
+    // Erasing the ECC leaves it to the erase of its data
+    algorithm.erase_sector(ecc_address).unwrap();
+    algorithm.blank_check(ecc_address, 8, 0xff).unwrap();
+
+    algorithm.program_page(ecc_address, &ecc).unwrap();
+    assert_eq!(read(ecc_address, 8), ecc);
+    assert_eq!(read(BANK1_SECTOR, 64), [0xff; 64]);
+    assert!(algorithm.blank_check(ecc_address, 8, 0xff).is_err());
+    drop(algorithm);
+
+    let mut algorithm: Algorithm = Algorithm::new(0, DEFAULT_CLOCK, Function::Verify).unwrap();
+    algorithm.verify(ecc_address, 8, Some(&ecc)).unwrap();
+}
@@ -0,0 +1,35 @@
+//! Deadlines for flash state machine operations, measured with the PMU cycle
+//! counter. A wedged FSM would otherwise hang the algorithm until probe-rs gives
+//! up on it, without any indication of what went wrong.
+
+use crate::f021;
+
+/// A point in time after which an operation is considered stuck.
+pub struct Deadline {
+    /// The cycle counter when the deadline was last checked
+    last: u32,
+    /// The number of cycles left until the deadline
+    remaining: u64,
+}
+
+impl Deadline {
+    /// A deadline `us` microseconds from now at an HCLK of `clock` MHz. The CPU runs
+    /// at least as fast as HCLK, so the deadline never comes early.
+    pub fn after(us: u32, clock: u32) -> Self {
+        f021::enable_cycle_counter();
+        Deadline {
+            last: f021::cycle_counter(),
+            remaining: us as u64 * clock as u64,
+        }
+    }
+
+    /// Return `true` once the deadline has passed. This must be called at least
+    /// once each time the 32-bit cycle counter wraps, which takes over 14 seconds.
+    pub fn expired(&mut self) -> bool {
+        let now = f021::cycle_counter();
+        let elapsed = now.wrapping_sub(self.last);
+        self.last = now;
+        self.remaining = self.remaining.saturating_sub(elapsed as u64);
+        self.remaining == 0
+    }
+}
@@ -0,0 +1,40 @@
+//! Flash wait states. The flash can only be read so quickly, so every HCLK above
+//! a datasheet limit needs another wait state. The F021 API refuses to initialize
+//! with `Error::InvalidHclkValue` if the wait states are too few for the HCLK.
+
+/// The datasheet limits of a device's flash
+pub struct WaitStateLimits {
+    /// The highest HCLK in MHz that the device is rated for
+    pub max_hclk_mhz: u32,
+    /// Each main flash data wait state (RWAIT) allows another this many MHz of HCLK
+    pub rwait_step_mhz: u32,
+    /// Each EEPROM emulation bank wait state (EWAIT) allows another this many MHz
+    pub ewait_step_mhz: u32,
+}
+
+/// RWAIT and EWAIT are both four-bit fields
+const MAX_WAIT_STATES: u32 = 15;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WaitStates {
+    pub rwait: u32,
+    pub ewait: u32,
+}
+
+impl WaitStates {
+    /// The fewest wait states that are safe at an HCLK of `hclk_mhz`. These are still
+    /// worked out from the limits if the device is overclocked, but are capped at
+    /// the most that the registers can hold.
+    pub fn for_hclk(hclk_mhz: u32, limits: &WaitStateLimits) -> Self {
+        WaitStates {
+            rwait: wait_states(hclk_mhz, limits.rwait_step_mhz),
+            ewait: wait_states(hclk_mhz, limits.ewait_step_mhz),
+        }
+    }
+}
+
+/// The number of wait states needed at `hclk_mhz`, if each one allows another
+/// `step_mhz`.
+fn wait_states(hclk_mhz: u32, step_mhz: u32) -> u32 {
+    (hclk_mhz.div_ceil(step_mhz).max(1) - 1).min(MAX_WAIT_STATES)
+}